@@ -41,6 +41,8 @@ module.exports = async function () {
   let mintAuthority
   let collateralAccount
   let syntheticUsd
+  let treasury
+  let insuranceFund
   let nonce
   let collateralTokenFeed
   nonce = _nonce
@@ -49,6 +51,8 @@ module.exports = async function () {
   collateralToken = await createToken({ connection, wallet, mintAuthority: admin.publicKey })
   collateralAccount = await collateralToken.createAccount(mintAuthority)
   syntheticUsd = await createToken({ connection, wallet, mintAuthority })
+  treasury = await syntheticUsd.createAccount(mintAuthority)
+  insuranceFund = await syntheticUsd.createAccount(mintAuthority)
   console.log(mintAuthority)
   await systemProgram.state.rpc.initialize(
     _nonce,
@@ -59,6 +63,9 @@ module.exports = async function () {
     collateralTokenFeed.publicKey,
     syntheticUsd.publicKey,
     mintAuthority,
+    oracleProgram.programId,
+    treasury,
+    insuranceFund,
     {
       accounts: {}
     }
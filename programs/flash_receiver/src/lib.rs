@@ -0,0 +1,47 @@
+#![feature(proc_macro_hygiene)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::invoke;
+use anchor_spl::token::TokenAccount;
+
+// Example receiver of system flash_mint used in tests. It is invoked by system program
+// so it can't call back into it, borrowed tokens can only be used in other programs.
+
+#[program]
+mod flash_receiver {
+    use super::*;
+
+    // Approves system authority to burn amount (borrowed amount with fee)
+    pub fn repay(ctx: Context<Repay>, amount: u64) -> ProgramResult {
+        if ctx.accounts.receiver.amount < amount {
+            return Err(ProgramError::InsufficientFunds);
+        }
+        let instruction = spl_token::instruction::approve(
+            ctx.accounts.token_program.key,
+            ctx.accounts.receiver.to_account_info().key,
+            ctx.accounts.authority.key,
+            ctx.accounts.owner.key,
+            &[],
+            amount,
+        )?;
+        invoke(
+            &instruction,
+            &[
+                ctx.accounts.receiver.to_account_info(),
+                ctx.accounts.authority.clone(),
+                ctx.accounts.owner.clone(),
+                ctx.accounts.token_program.clone(),
+            ],
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct Repay<'info> {
+    #[account(mut)]
+    pub receiver: CpiAccount<'info, TokenAccount>,
+    pub authority: AccountInfo<'info>,
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+}
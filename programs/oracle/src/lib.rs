@@ -20,7 +20,10 @@ mod oracle {
         counter.symbol = ticker;
         counter.admin = admin;
         counter.price = initial_price;
+        counter.confidence = 0;
         counter.paused = false;
+        counter.market_closed = false;
+        counter.last_update = ctx.accounts.clock.unix_timestamp;
         Ok(())
     }
 
@@ -30,9 +33,23 @@ mod oracle {
         Ok(())
     }
 
+    // Set by keeper outside of market hours of underlying
+    pub fn set_market_closed(ctx: Context<Pause>, market_closed: bool) -> ProgramResult {
+        let counter = &mut ctx.accounts.price_feed;
+        counter.market_closed = market_closed;
+        Ok(())
+    }
+
     pub fn set_price(ctx: Context<SetPrice>, price: u64) -> ProgramResult {
         let counter = &mut ctx.accounts.price_feed;
         counter.price = price;
+        counter.last_update = ctx.accounts.clock.unix_timestamp;
+        Ok(())
+    }
+
+    pub fn set_confidence(ctx: Context<SetPrice>, confidence: u64) -> ProgramResult {
+        let counter = &mut ctx.accounts.price_feed;
+        counter.confidence = confidence;
         Ok(())
     }
 }
@@ -44,6 +61,7 @@ pub struct Create<'info> {
     #[account(init)]
     pub price_feed: ProgramAccount<'info, PriceFeed>,
     pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
 }
 #[derive(Accounts)]
 pub struct Pause<'info> {
@@ -58,6 +76,7 @@ pub struct SetPrice<'info> {
     pub price_feed: ProgramAccount<'info, PriceFeed>,
     #[account(signer)]
     pub admin: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
 }
 
 // Define the program owned accounts.
@@ -68,4 +87,7 @@ pub struct PriceFeed {
     pub price: u64,
     pub paused: bool,
     pub symbol: Vec<u8>,
+    pub last_update: i64, // unix timestamp of last price change
+    pub market_closed: bool,
+    pub confidence: u64, // same offset as price
 }
@@ -1,6 +1,8 @@
 #![feature(proc_macro_hygiene)]
 
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke;
 use anchor_spl::token::{self, Burn, MintTo, TokenAccount, Transfer};
 mod math;
 use math::*;
@@ -16,7 +18,10 @@ pub mod system {
         pub nonce: u8,
         pub signer: Pubkey,
         pub admin: Pubkey,
+        pub guardian: Pubkey,
+        pub paused: u8,
         pub mint_authority: Pubkey,
+        pub oracle_program: Pubkey,
         pub initialized: bool,
         pub debt: u64,
         pub shares: u64,
@@ -24,19 +29,36 @@ pub mod system {
         pub collateral_token: Pubkey,
         pub collateral_account: Pubkey,
         pub collateralization_level: u32,
-        pub max_delay: u32,
-        pub fee: u8, // should be in range 0-99
+        pub max_delay: u32,        // in seconds
+        pub fee: u8,               // should be in range 0-99
+        pub debt_ceiling: u64,     // 0 means no ceiling
+        pub unbonding_period: u32, // in seconds, 0 means instant withdrawals
+        pub flash_mint_fee: u16,   // in basis points | 9 => 0.09%
+        pub treasury: Pubkey,
+        pub treasury_fee_share: u8, // percent of swap fee minted to treasury
+        pub insurance_fund: Pubkey,
+        pub insurance_fee_share: u8, // percent of swap fee minted to insurance fund
+        pub debt_interest_rate: u16, // yearly in basis points | 100 => 1%
+        pub last_interest_accrual: i64,
         pub assets: Vec<Asset>,
     }
 
     impl InternalState {
         pub const ASSETS_SIZE: usize = 10;
+        // Pause switches, guardian can only turn them on
+        pub const PAUSE_DEPOSIT: u8 = 1;
+        pub const PAUSE_WITHDRAW: u8 = 2;
+        pub const PAUSE_MINT: u8 = 4;
+        pub const PAUSE_BURN: u8 = 8;
+        pub const PAUSE_SWAP: u8 = 16;
+        pub const PAUSE_LIQUIDATION: u8 = 32;
         pub fn new(_ctx: Context<New>) -> Result<Self> {
             let mut assets: Vec<Asset> = vec![];
             assets.resize(
                 Self::ASSETS_SIZE,
                 Asset {
                     ticker: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+                    constituents: vec![IndexConstituent::default(); Asset::MAX_CONSTITUENTS],
                     ..Default::default()
                 },
             );
@@ -44,14 +66,26 @@ pub mod system {
                 nonce: 0,
                 signer: Pubkey::default(),
                 admin: Pubkey::default(),
+                guardian: Pubkey::default(),
+                paused: 0,
                 mint_authority: Pubkey::default(),
+                oracle_program: Pubkey::default(),
                 initialized: false,
                 debt: 0,
                 shares: 0,
                 collateral_balance: 0,
                 collateralization_level: 500, // 500%
-                max_delay: 1000,
-                fee: 30, // 0.3%
+                max_delay: 300,               // 5 minutes
+                fee: 30,                      // 0.3%
+                debt_ceiling: 0,
+                unbonding_period: 0,
+                flash_mint_fee: 9, // 0.09%
+                treasury: Pubkey::default(),
+                treasury_fee_share: 20, // 20% of fee
+                insurance_fund: Pubkey::default(),
+                insurance_fee_share: 10, // 10% of fee
+                debt_interest_rate: 0,
+                last_interest_accrual: 0,
                 collateral_token: Pubkey::default(),
                 collateral_account: Pubkey::default(),
                 assets,
@@ -68,6 +102,9 @@ pub mod system {
             collateral_token_feed: Pubkey,
             usd_token: Pubkey,
             mint_authority: Pubkey,
+            oracle_program: Pubkey,
+            treasury: Pubkey,
+            insurance_fund: Pubkey,
         ) -> Result<()> {
             self.initialized = true;
             self.signer = signer;
@@ -76,37 +113,45 @@ pub mod system {
             self.collateral_token = collateral_token;
             self.collateral_account = collateral_account;
             self.mint_authority = mint_authority;
+            self.oracle_program = oracle_program;
+            // Treasury and insurance fund are owned by the same signer as collateral account,
+            // so every instruction transferring with signer checks the source account key
+            self.treasury = treasury;
+            self.insurance_fund = insurance_fund;
             //clean asset array + add synthetic Usd
             let usd_asset = Asset {
-                decimals: 8,
-                asset_address: usd_token,
-                feed_address: Pubkey::default(), // unused
-                last_update: std::u64::MAX,
+                last_update: std::i64::MAX,
+                last_update_slot: std::u64::MAX,
                 price: 1 * 10u64.pow(4),
-                supply: 0,
-                ticker: "xUSD".as_bytes().to_vec(),
-            };
-            let collateral_asset = Asset {
-                decimals: 8,
-                asset_address: collateral_token,
-                feed_address: collateral_token_feed,
-                last_update: 0,
-                price: 0,
-                supply: 0,
-                ticker: "SNY".as_bytes().to_vec(),
+                ..Asset::new(
+                    usd_token,
+                    Pubkey::default(), // unused
+                    "xUSD".as_bytes().to_vec(),
+                )
             };
+            let collateral_asset = Asset::new(
+                collateral_token,
+                collateral_token_feed,
+                "SNY".as_bytes().to_vec(),
+            );
             self.assets = vec![usd_asset, collateral_asset];
             Ok(())
         }
         // This only support sythetic USD
         pub fn mint(&mut self, ctx: Context<Mint>, amount: u64) -> Result<()> {
+            if self.paused & Self::PAUSE_MINT != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
             let user_account = &mut ctx.accounts.user_account;
+            user_account
+                .check_authority(ctx.accounts.owner.key, UserAccount::PERMISSION_MINT_BURN)?;
+            user_account.check_destination(ctx.accounts.owner.key, &ctx.accounts.to.owner)?;
             let mint_token_adddress = ctx.accounts.mint.to_account_info().clone().key;
             if !mint_token_adddress.eq(&self.assets[0].asset_address) {
                 return Err(ErrorCode::NotSyntheticUsd.into());
             }
-            let slot = ctx.accounts.clock.slot;
-            let debt = calculate_debt(&self.assets, slot, self.max_delay).unwrap();
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
             let user_debt = calculate_user_debt_in_usd(user_account, debt, self.shares);
             let collateral_asset = self
                 .assets
@@ -131,6 +176,13 @@ pub mod system {
             if max_user_debt - user_debt < amount_mint_usd {
                 return Err(ErrorCode::MintLimit.into());
             }
+            if self.debt_ceiling != 0 && debt + amount_mint_usd > self.debt_ceiling {
+                return Err(ErrorCode::DebtCeiling.into());
+            }
+            if user_account.max_debt != 0 && user_debt + amount_mint_usd > user_account.max_debt {
+                return Err(ErrorCode::UserDebtCap.into());
+            }
+            check_supply_cap(&mint_asset, amount)?;
             let new_shares = calculate_new_shares(&self.shares, &debt, &amount_mint_usd);
             msg!("mint {}", 1234);
             self.debt = debt + amount_mint_usd;
@@ -145,9 +197,24 @@ pub mod system {
             Ok(())
         }
         pub fn withdraw(&mut self, ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+            if self.paused & Self::PAUSE_WITHDRAW != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            if self.unbonding_period != 0 {
+                return Err(ErrorCode::UnbondingRequired.into());
+            }
+            if !ctx
+                .accounts
+                .collateral_account
+                .to_account_info()
+                .key
+                .eq(&self.collateral_account)
+            {
+                return Err(ErrorCode::InvalidCollateralAccount.into());
+            }
             let user_account = &mut ctx.accounts.user_account;
-            let slot = ctx.accounts.clock.slot;
-            let debt = calculate_debt(&self.assets, slot, self.max_delay).unwrap();
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
             let user_debt = calculate_user_debt_in_usd(user_account, debt, self.shares);
 
             let collateral_asset = self
@@ -168,12 +235,11 @@ pub mod system {
                 &user_debt,
                 &self.collateralization_level,
             );
-            // TODO refactor move to math.rs
-            let max_amount_to_withdraw = max_withdraw_in_usd
-                * 10u64.pow(4) // oracle got 4 places offset 
-                / collateral_asset.price;
+            let max_amount_to_withdraw =
+                calculate_max_withdraw_in_token(&collateral_asset, max_withdraw_in_usd);
             msg!("max amount to withdraw : {:?}", max_amount_to_withdraw);
-            if max_amount_to_withdraw < amount {
+            // Escrowed collateral can't be withdrawn before vesting
+            if max_amount_to_withdraw < amount || user_account.collateral < amount {
                 return Err(ErrorCode::WithdrawError.into());
             }
             user_account.collateral -= amount;
@@ -185,6 +251,101 @@ pub mod system {
             Ok(())
         }
 
+        // Requested collateral stops counting toward mint capacity right away
+        pub fn request_withdrawal(
+            &mut self,
+            ctx: Context<RequestWithdrawal>,
+            amount: u64,
+        ) -> Result<()> {
+            if self.paused & Self::PAUSE_WITHDRAW != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            let user_account = &mut ctx.accounts.user_account;
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let user_debt = calculate_user_debt_in_usd(user_account, debt, self.shares);
+            let max_user_debt = calculate_max_user_debt_in_usd(
+                &self.assets[1],
+                self.collateralization_level,
+                user_account,
+            );
+            let max_withdraw_in_usd = calculate_max_withdraw_in_usd(
+                &max_user_debt,
+                &user_debt,
+                &self.collateralization_level,
+            );
+            let max_amount_to_withdraw =
+                calculate_max_withdraw_in_token(&self.assets[1], max_withdraw_in_usd);
+            // Escrowed collateral can't be withdrawn before vesting
+            if max_amount_to_withdraw < amount || user_account.collateral < amount {
+                return Err(ErrorCode::WithdrawError.into());
+            }
+            // New request restarts unbonding of the whole amount
+            user_account.collateral -= amount;
+            user_account.unbonding_amount += amount;
+            user_account.unbonding_since = timestamp;
+            Ok(())
+        }
+        pub fn claim_withdrawal(&mut self, ctx: Context<Withdraw>) -> Result<()> {
+            if self.paused & Self::PAUSE_WITHDRAW != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            if !ctx
+                .accounts
+                .collateral_account
+                .to_account_info()
+                .key
+                .eq(&self.collateral_account)
+            {
+                return Err(ErrorCode::InvalidCollateralAccount.into());
+            }
+            let user_account = &mut ctx.accounts.user_account;
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            if user_account.unbonding_since + (self.unbonding_period as i64) > timestamp {
+                return Err(ErrorCode::Unbonding.into());
+            }
+            let amount = user_account.unbonding_amount;
+            user_account.unbonding_amount = 0;
+            self.collateral_balance -= amount;
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::transfer(cpi_ctx, amount);
+            Ok(())
+        }
+        pub fn set_unbonding_period(
+            &mut self,
+            ctx: Context<SetParameter>,
+            unbonding_period: u32,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            self.unbonding_period = unbonding_period;
+            Ok(())
+        }
+
+        // Read only, meant to be simulated. Runtime has no return data for instructions
+        // so position is written to logs
+        pub fn get_user_position(&mut self, ctx: Context<GetUserPosition>) -> Result<()> {
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let position = calculate_user_position(
+                &self.assets[1],
+                self.collateralization_level,
+                &ctx.accounts.user_account,
+                debt,
+                self.shares,
+            );
+            msg!("collateral_value {}", position.collateral_value);
+            msg!("debt {}", position.debt);
+            msg!("pool_share {}", position.pool_share);
+            msg!("collateral_ratio {}", position.collateral_ratio);
+            msg!("max_mintable {}", position.max_mintable);
+            msg!("max_withdrawable {}", position.max_withdrawable);
+            msg!("liquidation_price {}", position.liquidation_price);
+            Ok(())
+        }
         pub fn add_asset(&mut self, ctx: Context<AddAsset>, ticker: Vec<u8>) -> Result<()> {
             if !self.admin.eq(ctx.accounts.admin.key) {
                 return Err(ErrorCode::Unauthorized.into());
@@ -193,19 +354,399 @@ pub mod system {
                 return Err(ErrorCode::AssetsFull.into());
             }
             // TODO add check if asset exist
+            let new_asset = Asset::new(
+                *ctx.accounts.asset_address.to_account_info().key,
+                *ctx.accounts.feed_address.to_account_info().key,
+                ticker,
+            );
+            self.assets.push(new_asset);
+            Ok(())
+        }
+        // Inverse asset shares price feed with underlying asset
+        pub fn add_inverse_asset(
+            &mut self,
+            ctx: Context<AddAsset>,
+            ticker: Vec<u8>,
+            entry_price: u64,
+            lower_limit: u64,
+            upper_limit: u64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if self.assets.len() == Self::ASSETS_SIZE {
+                return Err(ErrorCode::AssetsFull.into());
+            }
+            if lower_limit >= entry_price || upper_limit <= entry_price {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            let new_asset = Asset {
+                kind: Asset::KIND_INVERSE,
+                entry_price,
+                lower_limit,
+                upper_limit,
+                ..Asset::new(
+                    *ctx.accounts.asset_address.to_account_info().key,
+                    *ctx.accounts.feed_address.to_account_info().key,
+                    ticker,
+                )
+            };
+            self.assets.push(new_asset);
+            Ok(())
+        }
+        // Index asset has no feed of its own, it is priced by update_index_price
+        pub fn add_index_asset(
+            &mut self,
+            ctx: Context<AddIndexAsset>,
+            ticker: Vec<u8>,
+            feeds: Vec<Pubkey>,
+            weights: Vec<u64>,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if self.assets.len() == Self::ASSETS_SIZE {
+                return Err(ErrorCode::AssetsFull.into());
+            }
+            if feeds.len() == 0
+                || feeds.len() > Asset::MAX_CONSTITUENTS
+                || feeds.len() != weights.len()
+                || weights.iter().any(|x| *x == 0)
+            {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            let new_asset = Asset {
+                kind: Asset::KIND_INDEX,
+                constituents: feeds
+                    .iter()
+                    .zip(weights.iter())
+                    .map(|(feed_address, weight)| IndexConstituent {
+                        feed_address: *feed_address,
+                        weight: *weight,
+                    })
+                    .collect(),
+                ..Asset::new(
+                    *ctx.accounts.asset_address.to_account_info().key,
+                    Pubkey::default(),
+                    ticker,
+                )
+            };
+            self.assets.push(new_asset);
+            Ok(())
+        }
+        // Price stays at initial price until first rebalance sets feed checkpoint
+        pub fn add_leveraged_asset(
+            &mut self,
+            ctx: Context<AddAsset>,
+            ticker: Vec<u8>,
+            leverage: i8,
+            initial_price: u64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if self.assets.len() == Self::ASSETS_SIZE {
+                return Err(ErrorCode::AssetsFull.into());
+            }
+            if leverage == 0
+                || leverage < -Asset::MAX_LEVERAGE
+                || leverage > Asset::MAX_LEVERAGE
+                || initial_price == 0
+            {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
             let new_asset = Asset {
-                asset_address: *ctx.accounts.asset_address.to_account_info().key,
-                feed_address: *ctx.accounts.feed_address.to_account_info().key,
-                price: 0,
-                supply: 0,
-                last_update: 0,
-                decimals: 8,
-                ticker: ticker,
+                price: initial_price,
+                kind: Asset::KIND_LEVERAGED,
+                leverage,
+                checkpoint_price: initial_price,
+                ..Asset::new(
+                    *ctx.accounts.asset_address.to_account_info().key,
+                    *ctx.accounts.feed_address.to_account_info().key,
+                    ticker,
+                )
             };
             self.assets.push(new_asset);
             Ok(())
         }
+        // Permissionless, resets leverage once per rebalance period
+        pub fn rebalance(&mut self, ctx: Context<Rebalance>) -> Result<()> {
+            let price_feed = &ctx.accounts.price_feed_account;
+            let price_feed_info = price_feed.to_account_info();
+            if !price_feed_info.owner.eq(&self.oracle_program) {
+                return Err(ErrorCode::InvalidOracleProgram.into());
+            }
+            let asset = match self.assets.iter_mut().find(|x| {
+                x.asset_address == *ctx.accounts.asset_address.key
+                    && x.kind == Asset::KIND_LEVERAGED
+            }) {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            if !asset.feed_address.eq(price_feed_info.key) {
+                return Err(ErrorCode::FeedNotFound.into());
+            }
+            if asset.frozen {
+                return Err(ErrorCode::AssetFrozen.into());
+            }
+            if is_expired(asset, price_feed.last_update) {
+                return Err(ErrorCode::AssetExpired.into());
+            }
+            let clock = &ctx.accounts.clock;
+            if clock.unix_timestamp < asset.last_rebalance + Asset::REBALANCE_PERIOD {
+                return Err(ErrorCode::RebalanceTooEarly.into());
+            }
+            if clock.unix_timestamp - price_feed.last_update
+                > asset_max_delay(asset, self.max_delay) as i64
+            {
+                return Err(ErrorCode::OutdatedOracle.into());
+            }
+            set_asset_price(asset, price_feed.price, price_feed.confidence);
+            asset.last_update = price_feed.last_update;
+            asset.last_update_slot = clock.slot;
+            // Asset frozen at floor keeps its last checkpoint
+            if asset.frozen {
+                msg!("frozen at price {}", asset.price);
+                return Ok(());
+            }
+            asset.checkpoint_price = asset.price;
+            asset.checkpoint_feed_price = price_feed.price;
+            asset.last_rebalance = clock.unix_timestamp;
+            msg!("rebalanced at price {}", asset.price);
+            Ok(())
+        }
+        pub fn set_asset_max_delay(
+            &mut self,
+            ctx: Context<SetAssetParameter>,
+            max_delay: u32,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            let asset = match self
+                .assets
+                .iter_mut()
+                .find(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            asset.max_delay = max_delay;
+            Ok(())
+        }
+        pub fn set_asset_fee(&mut self, ctx: Context<SetAssetParameter>, fee: u8) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            let asset = match self
+                .assets
+                .iter_mut()
+                .find(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            asset.fee = fee;
+            Ok(())
+        }
+        pub fn set_asset_max_supply(
+            &mut self,
+            ctx: Context<SetAssetParameter>,
+            max_supply: u64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            let asset = match self
+                .assets
+                .iter_mut()
+                .find(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            asset.max_supply = max_supply;
+            Ok(())
+        }
+        pub fn set_asset_trading(
+            &mut self,
+            ctx: Context<SetAssetParameter>,
+            halted: bool,
+            trading_start: u32,
+            trading_end: u32,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if trading_start >= Asset::SECONDS_PER_DAY || trading_end >= Asset::SECONDS_PER_DAY {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            let asset = match self
+                .assets
+                .iter_mut()
+                .find(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            asset.halted = halted;
+            asset.trading_start = trading_start;
+            asset.trading_end = trading_end;
+            Ok(())
+        }
+        pub fn set_asset_expiry(
+            &mut self,
+            ctx: Context<SetAssetExpiry>,
+            expiry: i64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if expiry <= ctx.accounts.clock.unix_timestamp {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            // Synthetic USD and collateral can't expire
+            let asset = match self.assets[2..]
+                .iter_mut()
+                .find(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            if asset.frozen {
+                return Err(ErrorCode::AssetFrozen.into());
+            }
+            asset.expiry = expiry;
+            Ok(())
+        }
+        // Permissionless, freezes expired asset at last price recorded before expiry
+        pub fn settle(&mut self, ctx: Context<Settle>) -> Result<()> {
+            let asset = match self
+                .assets
+                .iter_mut()
+                .find(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(asset) => asset,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            if !is_expired(asset, ctx.accounts.clock.unix_timestamp) {
+                return Err(ErrorCode::NotExpired.into());
+            }
+            if asset.frozen {
+                return Err(ErrorCode::AssetFrozen.into());
+            }
+            // Last price has to be fresh at expiry
+            if is_outdated(asset, self.max_delay, ctx.accounts.clock.unix_timestamp) {
+                return Err(ErrorCode::OutdatedOracle.into());
+            }
+            asset.frozen = true;
+            asset.confidence = 0;
+            msg!("settled at price {}", asset.price);
+            Ok(())
+        }
+        // Holder has to approve authority to burn settled tokens
+        pub fn redeem(&mut self, ctx: Context<Redeem>, amount: u64) -> Result<()> {
+            if self.paused & Self::PAUSE_SWAP != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            if !ctx.accounts.usd_token.key.eq(&self.assets[0].asset_address) {
+                return Err(ErrorCode::NotSyntheticUsd.into());
+            }
+            if !ctx
+                .accounts
+                .user_token_account
+                .owner
+                .eq(ctx.accounts.owner.key)
+            {
+                return Err(ErrorCode::InvalidTokenAccount.into());
+            }
+            let asset_index = match self
+                .assets
+                .iter()
+                .position(|x| x.asset_address == *ctx.accounts.mint.key)
+            {
+                Some(index) => index,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            let asset = &self.assets[asset_index];
+            // Assets frozen at limit before expiry can't be redeemed yet
+            if !is_expired(asset, ctx.accounts.clock.unix_timestamp) || !asset.frozen {
+                return Err(ErrorCode::NotSettled.into());
+            }
+            // Settled value is moved to synthetic USD so debt does not change
+            let amount_usd = calculate_amount_mint_in_usd(asset, amount);
+            self.assets[asset_index].supply -= amount;
+            self.assets[0].supply += amount_usd;
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+
+            let cpi_ctx_burn: CpiContext<Burn> =
+                CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::burn(cpi_ctx_burn, amount);
+
+            let cpi_ctx_mint: CpiContext<MintTo> =
+                CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::mint_to(cpi_ctx_mint, amount_usd);
+            Ok(())
+        }
+        // Only settled assets without supply can be removed
+        pub fn delist_asset(&mut self, ctx: Context<DelistAsset>) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            let asset_index = match self
+                .assets
+                .iter()
+                .position(|x| x.asset_address == *ctx.accounts.asset_address.key)
+            {
+                Some(index) => index,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            let asset = &self.assets[asset_index];
+            if !is_expired(asset, ctx.accounts.clock.unix_timestamp)
+                || !asset.frozen
+                || asset.supply != 0
+            {
+                return Err(ErrorCode::AssetNotDelistable.into());
+            }
+            self.assets.remove(asset_index);
+            Ok(())
+        }
+        pub fn set_debt_ceiling(
+            &mut self,
+            ctx: Context<SetParameter>,
+            debt_ceiling: u64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            self.debt_ceiling = debt_ceiling;
+            Ok(())
+        }
+        pub fn set_user_max_debt(
+            &mut self,
+            ctx: Context<SetUserMaxDebt>,
+            max_debt: u64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            ctx.accounts.user_account.max_debt = max_debt;
+            Ok(())
+        }
         pub fn deposit(&mut self, ctx: Context<Deposit>) -> Result<()> {
+            if self.paused & Self::PAUSE_DEPOSIT != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            if !ctx
+                .accounts
+                .collateral_account
+                .to_account_info()
+                .key
+                .eq(&self.collateral_account)
+            {
+                return Err(ErrorCode::InvalidCollateralAccount.into());
+            }
             let new_balance = ctx.accounts.collateral_account.amount;
             let deposited = new_balance - self.collateral_balance;
             if deposited == 0 {
@@ -216,115 +757,803 @@ pub mod system {
             self.collateral_balance = new_balance;
             Ok(())
         }
-        pub fn update_price(
+        // Escrows collateral transferred to collateral account in the same transaction
+        pub fn create_vesting(
             &mut self,
-            ctx: Context<UpdatePrice>,
-            feed_address: Pubkey,
+            ctx: Context<CreateVesting>,
+            start: i64,
+            end: i64,
         ) -> Result<()> {
-            msg!("#################################");
-            let asset = self
-                .assets
-                .iter_mut()
-                .find(|x| x.feed_address == feed_address)
-                .unwrap();
-            let slot = ctx.accounts.clock.slot;
-            msg!("updated slot {}", slot);
-            asset.price = ctx.accounts.price_feed_account.price;
-            asset.last_update = slot;
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if end <= start {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            if !ctx
+                .accounts
+                .collateral_account
+                .to_account_info()
+                .key
+                .eq(&self.collateral_account)
+            {
+                return Err(ErrorCode::InvalidCollateralAccount.into());
+            }
+            let new_balance = ctx.accounts.collateral_account.amount;
+            let deposited = new_balance - self.collateral_balance;
+            if deposited == 0 {
+                return Err(ErrorCode::ZeroDeposit.into());
+            }
+            let user_account = &mut ctx.accounts.user_account;
+            if user_account.vesting.len() >= UserAccount::MAX_VESTING_ENTRIES {
+                return Err(ErrorCode::TooManyVestingEntries.into());
+            }
+            user_account.vesting.push(VestingEntry {
+                amount: deposited,
+                claimed: 0,
+                start,
+                end,
+            });
+            self.collateral_balance = new_balance;
             Ok(())
         }
-        pub fn burn(&mut self, ctx: Context<BurnToken>, amount: u64) -> Result<()> {
-            let user_account = &mut ctx.accounts.user_account;
-            let token_address = ctx.accounts.mint.key;
-            let slot = ctx.accounts.clock.slot;
-            let debt = calculate_debt(&self.assets, slot, self.max_delay).unwrap();
-            let burn_asset = self
-                .assets
-                .iter_mut()
-                .find(|x| x.asset_address == *token_address)
-                .unwrap();
-
-            let user_debt = calculate_user_debt_in_usd(user_account, debt, self.shares);
-
-            let burned_shares =
-                calculate_burned_shares(&burn_asset, &user_debt, &user_account.shares, &amount);
-            // msg!("Burned shares {}", burned_shares);
-            // msg!("User shares {}", user_account.shares);
-            if burned_shares > user_account.shares {
-                let burned_amount = calculate_max_burned_in_token(burn_asset, &user_debt);
-                burn_asset.supply -= burned_amount;
-                self.shares -= user_account.shares;
-                user_account.shares = 0;
-                let seeds = &[self.signer.as_ref(), &[self.nonce]];
-                let signer = &[&seeds[..]];
-                let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
-                token::burn(cpi_ctx, burned_amount);
-                Ok(())
-            } else {
-                burn_asset.supply -= amount;
-                user_account.shares -= burned_shares;
-                self.shares -= burned_shares;
-                let seeds = &[self.signer.as_ref(), &[self.nonce]];
-                let signer = &[&seeds[..]];
-                let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
-                token::burn(cpi_ctx, amount);
-                Ok(())
-            }
-        }
-        pub fn swap(&mut self, ctx: Context<Swap>, amount: u64) -> Result<()> {
-            let user_account = &mut ctx.accounts.user_account;
-            // We allow washtrading
-            let token_address_in = ctx.accounts.token_in.key;
-            let token_address_for = ctx.accounts.token_for.key;
-            let slot = ctx.accounts.clock.slot;
-
-            if token_address_for.eq(&self.assets[1].asset_address) {
-                return Err(ErrorCode::SyntheticCollateral.into());
+        pub fn update_price(&mut self, ctx: Context<UpdatePrice>) -> Result<()> {
+            let price_feed = &ctx.accounts.price_feed_account;
+            let price_feed_info = price_feed.to_account_info();
+            if !price_feed_info.owner.eq(&self.oracle_program) {
+                return Err(ErrorCode::InvalidOracleProgram.into());
+            }
+            let clock = &ctx.accounts.clock;
+            let found = self.apply_price_feed(price_feed_info.key, price_feed, clock);
+            if !found {
+                return Err(ErrorCode::FeedNotFound.into());
+            }
+            msg!(
+                "updated slot {} timestamp {}",
+                clock.slot,
+                clock.unix_timestamp
+            );
+            Ok(())
+        }
+        // Constituent price feeds are passed as remaining accounts in order
+        pub fn update_index_price(&mut self, ctx: Context<UpdateIndexPrice>) -> Result<()> {
+            let asset_index = match self.assets.iter().position(|x| {
+                x.asset_address == *ctx.accounts.asset_address.key && x.kind == Asset::KIND_INDEX
+            }) {
+                Some(index) => index,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            let constituents = &self.assets[asset_index].constituents;
+            if ctx.remaining_accounts.len() != constituents.len() {
+                return Err(ErrorCode::InvalidConstituents.into());
             }
-            let asset_in_index = self
+            let mut prices: Vec<u64> = vec![];
+            let mut confidences: Vec<u64> = vec![];
+            // Index is as fresh as its oldest constituent
+            let mut last_update = std::i64::MAX;
+            let mut market_closed = false;
+            for (constituent, price_feed_info) in
+                constituents.iter().zip(ctx.remaining_accounts.iter())
+            {
+                if !price_feed_info.key.eq(&constituent.feed_address) {
+                    return Err(ErrorCode::InvalidConstituents.into());
+                }
+                if !price_feed_info.owner.eq(&self.oracle_program) {
+                    return Err(ErrorCode::InvalidOracleProgram.into());
+                }
+                let price_feed: CpiAccount<PriceFeed> = CpiAccount::try_from(price_feed_info)?;
+                if is_expired(&self.assets[asset_index], price_feed.last_update) {
+                    return Err(ErrorCode::AssetExpired.into());
+                }
+                prices.push(price_feed.price);
+                confidences.push(price_feed.confidence);
+                last_update = std::cmp::min(last_update, price_feed.last_update);
+                market_closed = market_closed || price_feed.market_closed;
+            }
+            let (price, confidence) = calculate_index_price(constituents, &prices, &confidences);
+            let asset = &mut self.assets[asset_index];
+            asset.price = price;
+            asset.confidence = confidence;
+            asset.market_closed = market_closed;
+            asset.last_update = last_update;
+            asset.last_update_slot = ctx.accounts.clock.slot;
+            Ok(())
+        }
+        // Price feeds are passed as remaining accounts, feeds that can't be used are skipped
+        pub fn update_prices(&mut self, ctx: Context<UpdatePrices>) -> Result<()> {
+            let clock = &ctx.accounts.clock;
+            for price_feed_info in ctx.remaining_accounts.iter() {
+                if !price_feed_info.owner.eq(&self.oracle_program) {
+                    msg!(
+                        "skipped {}: not owned by oracle program",
+                        price_feed_info.key
+                    );
+                    continue;
+                }
+                let price_feed: CpiAccount<PriceFeed> = match CpiAccount::try_from(price_feed_info)
+                {
+                    Ok(price_feed) => price_feed,
+                    Err(_) => {
+                        msg!("skipped {}: not a price feed", price_feed_info.key);
+                        continue;
+                    }
+                };
+                let found = self.apply_price_feed(price_feed_info.key, &price_feed, clock);
+                if !found {
+                    msg!(
+                        "skipped {}: no asset uses this price feed",
+                        price_feed_info.key
+                    );
+                }
+            }
+            Ok(())
+        }
+        pub fn burn(
+            &mut self,
+            ctx: Context<BurnToken>,
+            amount: u64,
+            min_debt_repaid: u64,
+        ) -> Result<()> {
+            if self.paused & Self::PAUSE_BURN != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            ctx.accounts
+                .user_account
+                .check_authority(ctx.accounts.owner.key, UserAccount::PERMISSION_MINT_BURN)?;
+            if !ctx
+                .accounts
+                .user_token_account
+                .owner
+                .eq(ctx.accounts.owner.key)
+            {
+                return Err(ErrorCode::InvalidTokenAccount.into());
+            }
+            let burn_asset_index = match self
                 .assets
                 .iter()
-                .position(|x| x.asset_address == *token_address_in)
-                .unwrap();
-            let asset_for_index = self
+                .position(|x| x.asset_address == *ctx.accounts.mint.key)
+            {
+                Some(index) => index,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            let burned_amount = self.burn_debt(
+                &mut ctx.accounts.user_account,
+                burn_asset_index,
+                amount,
+                min_debt_repaid,
+                ctx.accounts.user_token_account.amount,
+                ctx.accounts.clock.unix_timestamp,
+            )?;
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::burn(cpi_ctx, burned_amount);
+            Ok(())
+        }
+        // Anyone can burn own synthetics to reduce debt of other account
+        pub fn repay_for(
+            &mut self,
+            ctx: Context<RepayFor>,
+            amount: u64,
+            min_debt_repaid: u64,
+        ) -> Result<()> {
+            if self.paused & Self::PAUSE_BURN != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            if !ctx
+                .accounts
+                .payer_token_account
+                .owner
+                .eq(ctx.accounts.payer.key)
+            {
+                return Err(ErrorCode::InvalidTokenAccount.into());
+            }
+            let burn_asset_index = match self
                 .assets
                 .iter()
-                .position(|x| x.asset_address == *token_address_for)
-                .unwrap();
-            check_feed_update(
-                &self.assets,
+                .position(|x| x.asset_address == *ctx.accounts.mint.key)
+            {
+                Some(index) => index,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            let burned_amount = self.burn_debt(
+                &mut ctx.accounts.user_account,
+                burn_asset_index,
+                amount,
+                min_debt_repaid,
+                ctx.accounts.payer_token_account.amount,
+                ctx.accounts.clock.unix_timestamp,
+            )?;
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::burn(cpi_ctx, burned_amount);
+            Ok(())
+        }
+        pub fn swap(&mut self, ctx: Context<Swap>, amount: u64, min_amount_out: u64) -> Result<()> {
+            self.check_swap(&ctx.accounts)?;
+            // We allow washtrading
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let asset_in_index =
+                self.find_swap_asset(ctx.accounts.token_in.key, timestamp, false)?;
+            let asset_for_index =
+                self.find_swap_asset(ctx.accounts.token_for.key, timestamp, true)?;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let fee = calculate_swap_fee(
+                &self.assets[asset_in_index],
+                &self.assets[asset_for_index],
+                self.fee,
+                debt,
+            );
+            let amount_for = calculate_swap_out_amount(
+                &self.assets[asset_in_index],
+                &self.assets[asset_for_index],
+                &amount,
+                &fee,
+            );
+            if amount_for < min_amount_out {
+                return Err(ErrorCode::Slippage.into());
+            }
+            self.settle_swap(
+                &ctx.accounts,
                 asset_in_index,
                 asset_for_index,
-                self.max_delay,
-                slot,
+                amount,
+                amount_for,
+                fee,
+                debt,
             )
-            .unwrap();
-            let amount_for = calculate_swap_out_amount(
+        }
+        pub fn swap_exact_out(
+            &mut self,
+            ctx: Context<Swap>,
+            amount_for: u64,
+            max_amount_in: u64,
+        ) -> Result<()> {
+            self.check_swap(&ctx.accounts)?;
+            // We allow washtrading
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let asset_in_index =
+                self.find_swap_asset(ctx.accounts.token_in.key, timestamp, false)?;
+            let asset_for_index =
+                self.find_swap_asset(ctx.accounts.token_for.key, timestamp, true)?;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let fee = calculate_swap_fee(
                 &self.assets[asset_in_index],
                 &self.assets[asset_for_index],
-                &amount,
-                &self.fee,
+                self.fee,
+                debt,
+            );
+            let amount = calculate_swap_in_amount(
+                &self.assets[asset_in_index],
+                &self.assets[asset_for_index],
+                &amount_for,
+                &fee,
+            );
+            if amount > max_amount_in {
+                return Err(ErrorCode::Slippage.into());
+            }
+            self.settle_swap(
+                &ctx.accounts,
+                asset_in_index,
+                asset_for_index,
+                amount,
+                amount_for,
+                fee,
+                debt,
+            )
+        }
+        // Intermediate assets of the route are passed as remaining accounts
+        pub fn swap_route(
+            &mut self,
+            ctx: Context<Swap>,
+            amount: u64,
+            min_amount_out: u64,
+        ) -> Result<()> {
+            self.check_swap(&ctx.accounts)?;
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let mut route_addresses = vec![ctx.accounts.token_in.key];
+            route_addresses.extend(ctx.remaining_accounts.iter().map(|x| x.key));
+            route_addresses.push(ctx.accounts.token_for.key);
+            if route_addresses.len() > Self::ASSETS_SIZE {
+                return Err(ErrorCode::RouteTooLong.into());
+            }
+            let mut route: Vec<usize> = vec![];
+            for (hop, address) in route_addresses.iter().enumerate() {
+                route.push(self.find_swap_asset(address, timestamp, hop > 0)?);
+            }
+            let asset_in_index = route[0];
+            let asset_for_index = route[route.len() - 1];
+
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let fee = calculate_swap_fee(
+                &self.assets[asset_in_index],
+                &self.assets[asset_for_index],
+                self.fee,
+                debt,
             );
-            self.assets[asset_in_index].supply -= amount;
-            self.assets[asset_for_index].supply += amount_for;
+            let route_assets: Vec<&Asset> = route.iter().map(|x| &self.assets[*x]).collect();
+            let amount_for = calculate_route_out_amount(&route_assets, &amount, &fee);
+            if amount_for < min_amount_out {
+                return Err(ErrorCode::Slippage.into());
+            }
+            self.settle_swap(
+                &ctx.accounts,
+                asset_in_index,
+                asset_for_index,
+                amount,
+                amount_for,
+                fee,
+                debt,
+            )
+        }
+        // Receiver has to approve authority to burn amount with fee during the call.
+        // Solana does not allow reentrancy so receiver program can't call back into
+        // this program, borrowed tokens can only be used in other programs
+        pub fn flash_mint(
+            &mut self,
+            ctx: Context<FlashMint>,
+            amount: u64,
+            data: Vec<u8>,
+        ) -> Result<()> {
+            if self.paused & Self::PAUSE_MINT != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            let asset_index = match self
+                .assets
+                .iter()
+                .position(|x| x.asset_address == *ctx.accounts.mint.key)
+            {
+                Some(index) => index,
+                None => return Err(ErrorCode::AssetNotFound.into()),
+            };
+            if ctx.accounts.mint.key.eq(&self.collateral_token) {
+                return Err(ErrorCode::SyntheticCollateral.into());
+            }
+            // Only tokens of initiator can be burned
+            if !ctx.accounts.receiver.owner.eq(ctx.accounts.initiator.key) {
+                return Err(ErrorCode::InvalidTokenAccount.into());
+            }
+            check_supply_cap(&self.assets[asset_index], amount)?;
+            let fee = calculate_flash_mint_fee(amount, self.flash_mint_fee);
             let seeds = &[self.signer.as_ref(), &[self.nonce]];
             let signer = &[&seeds[..]];
 
-            let cpi_ctx_burn: CpiContext<Burn> =
+            let cpi_ctx_mint: CpiContext<MintTo> =
                 CpiContext::from(&*ctx.accounts).with_signer(signer);
-            token::burn(cpi_ctx_burn, amount);
+            token::mint_to(cpi_ctx_mint, amount)?;
 
-            let cpi_ctx_mint: CpiContext<MintTo> =
+            let instruction = Instruction {
+                program_id: *ctx.accounts.receiver_program.key,
+                accounts: ctx
+                    .remaining_accounts
+                    .iter()
+                    .map(|x| AccountMeta {
+                        pubkey: *x.key,
+                        is_signer: x.is_signer,
+                        is_writable: x.is_writable,
+                    })
+                    .collect(),
+                data,
+            };
+            let mut account_infos = ctx.remaining_accounts.to_vec();
+            account_infos.push(ctx.accounts.receiver_program.clone());
+            invoke(&instruction, &account_infos)?;
+
+            // Fails if receiver did not return amount with fee
+            let cpi_ctx_burn: CpiContext<Burn> =
                 CpiContext::from(&*ctx.accounts).with_signer(signer);
-            token::mint_to(cpi_ctx_mint, amount_for);
+            token::burn(cpi_ctx_burn, amount + fee)?;
+            // Burned fee lowers debt of all holders
+            self.assets[asset_index].supply -= fee;
+            Ok(())
+        }
+        pub fn set_flash_mint_fee(
+            &mut self,
+            ctx: Context<SetParameter>,
+            flash_mint_fee: u16,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if flash_mint_fee > 10000 {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            self.flash_mint_fee = flash_mint_fee;
+            Ok(())
+        }
+        pub fn set_paused(&mut self, ctx: Context<SetParameter>, paused: u8) -> Result<()> {
+            let signer = ctx.accounts.admin.key;
+            if self.admin.eq(signer) {
+                self.paused = paused;
+                return Ok(());
+            }
+            if self.guardian.eq(&Pubkey::default()) || !self.guardian.eq(signer) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if paused & self.paused != self.paused {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            self.paused = paused;
+            Ok(())
+        }
+        pub fn set_guardian(&mut self, ctx: Context<SetParameter>, guardian: Pubkey) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            self.guardian = guardian;
+            Ok(())
+        }
+        pub fn set_treasury_fee_share(
+            &mut self,
+            ctx: Context<SetParameter>,
+            treasury_fee_share: u8,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if treasury_fee_share as u16 + self.insurance_fee_share as u16 > 100 {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            self.treasury_fee_share = treasury_fee_share;
+            Ok(())
+        }
+        pub fn set_insurance_fee_share(
+            &mut self,
+            ctx: Context<SetParameter>,
+            insurance_fee_share: u8,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if self.treasury_fee_share as u16 + insurance_fee_share as u16 > 100 {
+                return Err(ErrorCode::ParameterOutOfRange.into());
+            }
+            self.insurance_fee_share = insurance_fee_share;
+            Ok(())
+        }
+        // Permissionless, covers shortfall of undercollateralized user from insurance fund
+        // and socializes the rest over all debt holders
+        pub fn cover_bad_debt(&mut self, ctx: Context<CoverBadDebt>) -> Result<()> {
+            if self.paused & Self::PAUSE_LIQUIDATION != 0 {
+                return Err(ErrorCode::Paused.into());
+            }
+            if !ctx.accounts.mint.key.eq(&self.assets[0].asset_address) {
+                return Err(ErrorCode::NotSyntheticUsd.into());
+            }
+            if !ctx
+                .accounts
+                .insurance_fund
+                .to_account_info()
+                .key
+                .eq(&self.insurance_fund)
+            {
+                return Err(ErrorCode::InvalidInsuranceFund.into());
+            }
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let user_account = &mut ctx.accounts.user_account;
+            let user_debt = calculate_user_debt_in_usd(user_account, debt, self.shares);
+            let shortfall = calculate_bad_debt(&self.assets[1], user_account, user_debt);
+            if shortfall == 0 {
+                return Err(ErrorCode::NoBadDebt.into());
+            }
+            let covered = std::cmp::min(shortfall, ctx.accounts.insurance_fund.amount);
+            msg!(
+                "bad debt {}, covered by insurance fund {}, seized collateral {}",
+                shortfall,
+                covered,
+                user_account.collateral
+            );
+            // Position is closed, seized collateral stays in collateral account without owner
+            // (collateral_balance is unchanged) and uncovered debt is spread over other shares
+            self.shares -= user_account.shares;
+            user_account.shares = 0;
+            user_account.collateral = 0;
+            self.debt = debt - covered;
+            self.assets[0].supply -= covered;
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::burn(cpi_ctx, covered);
             Ok(())
         }
+        pub fn withdraw_treasury(
+            &mut self,
+            ctx: Context<WithdrawTreasury>,
+            amount: u64,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if !ctx.accounts.treasury.key.eq(&self.treasury) {
+                return Err(ErrorCode::InvalidTreasury.into());
+            }
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::transfer(cpi_ctx, amount);
+            Ok(())
+        }
+        // Permissionless, mints interest accrued since last call to treasury
+        pub fn accrue_interest(&mut self, ctx: Context<AccrueInterest>) -> Result<()> {
+            if !ctx.accounts.mint.key.eq(&self.assets[0].asset_address) {
+                return Err(ErrorCode::NotSyntheticUsd.into());
+            }
+            if !ctx.accounts.treasury.key.eq(&self.treasury) {
+                return Err(ErrorCode::InvalidTreasury.into());
+            }
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+            let interest = calculate_interest(
+                debt,
+                self.debt_interest_rate,
+                timestamp - self.last_interest_accrual,
+            );
+            msg!("accrued interest {}", interest);
+            self.last_interest_accrual = timestamp;
+            self.debt = debt + interest;
+            self.assets[0].supply += interest;
+            let seeds = &[self.signer.as_ref(), &[self.nonce]];
+            let signer = &[&seeds[..]];
+            let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+            token::mint_to(cpi_ctx, interest);
+            Ok(())
+        }
+        // Interest have to be accrued in the same transaction before changing rate
+        pub fn set_debt_interest_rate(
+            &mut self,
+            ctx: Context<SetDebtInterestRate>,
+            debt_interest_rate: u16,
+        ) -> Result<()> {
+            if !self.admin.eq(ctx.accounts.admin.key) {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            let timestamp = ctx.accounts.clock.unix_timestamp;
+            if self.debt_interest_rate != 0 && self.last_interest_accrual != timestamp {
+                return Err(ErrorCode::InterestNotAccrued.into());
+            }
+            self.debt_interest_rate = debt_interest_rate;
+            self.last_interest_accrual = timestamp;
+            Ok(())
+        }
+    }
+    pub fn create_user_account(ctx: Context<CreateUserAccount>, owner: Pubkey) -> ProgramResult {
+        let user_account = &mut ctx.accounts.user_account;
+        user_account.owner = owner;
+        user_account.shares = 0;
+        user_account.collateral = 0;
+        user_account.delegate = Pubkey::default();
+        user_account.delegate_permissions = 0;
+        user_account.max_debt = 0;
+        user_account.unbonding_amount = 0;
+        user_account.unbonding_since = 0;
+        user_account.vesting = vec![];
+        Ok(())
+    }
+    pub fn set_delegate(
+        ctx: Context<SetDelegate>,
+        delegate: Pubkey,
+        delegate_permissions: u8,
+    ) -> ProgramResult {
+        let user_account = &mut ctx.accounts.user_account;
+        user_account.delegate = delegate;
+        user_account.delegate_permissions = delegate_permissions;
+        Ok(())
+    }
+    // Moves vested escrow into withdrawable collateral
+    pub fn claim_vested(ctx: Context<ClaimVested>) -> ProgramResult {
+        let user_account = &mut ctx.accounts.user_account;
+        let timestamp = ctx.accounts.clock.unix_timestamp;
+        let mut claimed = 0;
+        for entry in user_account.vesting.iter_mut() {
+            let vested = calculate_vested(entry, timestamp);
+            claimed += vested - entry.claimed;
+            entry.claimed = vested;
+        }
+        user_account.vesting.retain(|x| x.claimed < x.amount);
+        user_account.collateral += claimed;
+        Ok(())
+    }
+    pub fn revoke_delegate(ctx: Context<SetDelegate>) -> ProgramResult {
+        let user_account = &mut ctx.accounts.user_account;
+        user_account.delegate = Pubkey::default();
+        user_account.delegate_permissions = 0;
+        Ok(())
+    }
+}
+
+// Shared by instructions, kept out of #[program] so they are not exposed as instructions
+impl system::InternalState {
+    // Every asset using this feed is updated, returns false if there is none
+    fn apply_price_feed(
+        &mut self,
+        feed_address: &Pubkey,
+        price_feed: &PriceFeed,
+        clock: &Clock,
+    ) -> bool {
+        let mut found = false;
+        for asset in self
+            .assets
+            .iter_mut()
+            .filter(|x| x.feed_address == *feed_address)
+        {
+            found = true;
+            // Prices published after expiry don't change expired asset
+            if is_expired(asset, price_feed.last_update) {
+                continue;
+            }
+            set_asset_price(asset, price_feed.price, price_feed.confidence);
+            asset.market_closed = price_feed.market_closed;
+            asset.last_update = price_feed.last_update;
+            asset.last_update_slot = clock.slot;
+        }
+        found
+    }
+    // Returns amount of tokens to burn, burning more than user debt only burns tokens
+    // worth the whole debt
+    fn burn_debt(
+        &mut self,
+        user_account: &mut UserAccount,
+        asset_index: usize,
+        amount: u64,
+        min_debt_repaid: u64,
+        token_account_amount: u64,
+        timestamp: i64,
+    ) -> Result<u64> {
+        check_feed_update(
+            &self.assets,
+            asset_index,
+            asset_index,
+            self.max_delay,
+            timestamp,
+        )?;
+        let debt = calculate_debt(&self.assets, timestamp, self.max_delay)?;
+        let user_debt = calculate_user_debt_in_usd(user_account, debt, self.shares);
+        if user_debt == 0 {
+            return Err(ErrorCode::NoDebt.into());
+        }
+        let (burned_amount, burned_shares, debt_repaid) = calculate_burn(
+            &self.assets[asset_index],
+            &user_debt,
+            &user_account.shares,
+            &amount,
+        );
+        if debt_repaid < min_debt_repaid {
+            return Err(ErrorCode::Slippage.into());
+        }
+        if token_account_amount < burned_amount {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        self.assets[asset_index].supply -= burned_amount;
+        self.debt = debt - debt_repaid;
+        self.shares -= burned_shares;
+        user_account.shares -= burned_shares;
+        msg!(
+            "burned {} tokens, repaid {} of debt",
+            burned_amount,
+            debt_repaid
+        );
+        Ok(burned_amount)
     }
-    pub fn create_user_account(ctx: Context<CreateUserAccount>, owner: Pubkey) -> ProgramResult {
-        let user_account = &mut ctx.accounts.user_account;
-        user_account.owner = owner;
-        user_account.shares = 0;
-        user_account.collateral = 0;
+    fn check_swap(&self, accounts: &Swap) -> Result<()> {
+        if self.paused & Self::PAUSE_SWAP != 0 {
+            return Err(ErrorCode::Paused.into());
+        }
+        accounts
+            .user_account
+            .check_authority(accounts.owner.key, UserAccount::PERMISSION_SWAP)?;
+        accounts
+            .user_account
+            .check_destination(accounts.owner.key, &accounts.user_token_account_for.owner)?;
+        // Only tokens of user account owner can be burned
+        if !accounts
+            .user_token_account_in
+            .owner
+            .eq(&accounts.user_account.owner)
+        {
+            return Err(ErrorCode::InvalidTokenAccount.into());
+        }
+        if !accounts.usd_token.key.eq(&self.assets[0].asset_address) {
+            return Err(ErrorCode::NotSyntheticUsd.into());
+        }
+        if !accounts.treasury.key.eq(&self.treasury) {
+            return Err(ErrorCode::InvalidTreasury.into());
+        }
+        if !accounts.insurance_fund.key.eq(&self.insurance_fund) {
+            return Err(ErrorCode::InvalidInsuranceFund.into());
+        }
+        Ok(())
+    }
+    // Assets swapped for can't be collateral, frozen or expired
+    fn find_swap_asset(
+        &self,
+        address: &Pubkey,
+        timestamp: i64,
+        swapped_for: bool,
+    ) -> Result<usize> {
+        if swapped_for && address.eq(&self.assets[1].asset_address) {
+            return Err(ErrorCode::SyntheticCollateral.into());
+        }
+        let index = match self.assets.iter().position(|x| x.asset_address == *address) {
+            Some(index) => index,
+            None => return Err(ErrorCode::AssetNotFound.into()),
+        };
+        let asset = &self.assets[index];
+        if swapped_for && asset.frozen {
+            return Err(ErrorCode::AssetFrozen.into());
+        }
+        if swapped_for && is_expired(asset, timestamp) {
+            return Err(ErrorCode::AssetExpired.into());
+        }
+        if is_trading_halted(asset, timestamp) {
+            return Err(ErrorCode::TradingHalted.into());
+        }
+        if is_outdated(asset, self.max_delay, timestamp) {
+            return Err(ErrorCode::OutdatedOracle.into());
+        }
+        Ok(index)
+    }
+    fn settle_swap(
+        &mut self,
+        accounts: &Swap,
+        asset_in_index: usize,
+        asset_for_index: usize,
+        amount: u64,
+        amount_for: u64,
+        fee: u16,
+        debt: u64,
+    ) -> Result<()> {
+        check_supply_cap(&self.assets[asset_for_index], amount_for)?;
+        let treasury_fee = calculate_fee_share(
+            &self.assets[asset_in_index],
+            &amount,
+            &fee,
+            &self.treasury_fee_share,
+        );
+        let insurance_fee = calculate_fee_share(
+            &self.assets[asset_in_index],
+            &amount,
+            &fee,
+            &self.insurance_fee_share,
+        );
+        // Fees minted to treasury and insurance fund are new debt
+        if self.debt_ceiling != 0 && debt + treasury_fee + insurance_fee > self.debt_ceiling {
+            return Err(ErrorCode::DebtCeiling.into());
+        }
+        self.assets[asset_in_index].supply -= amount;
+        self.assets[asset_for_index].supply += amount_for;
+        self.assets[0].supply += treasury_fee + insurance_fee;
+        let seeds = &[self.signer.as_ref(), &[self.nonce]];
+        let signer = &[&seeds[..]];
+
+        let cpi_ctx_burn: CpiContext<Burn> = CpiContext::from(accounts).with_signer(signer);
+        token::burn(cpi_ctx_burn, amount);
+
+        let cpi_ctx_mint: CpiContext<MintTo> = CpiContext::from(accounts).with_signer(signer);
+        token::mint_to(cpi_ctx_mint, amount_for);
+
+        // Rest of the fee stays with debt holders as it is never minted
+        let cpi_ctx_treasury = CpiContext::new(
+            accounts.token_program.clone(),
+            MintTo {
+                mint: accounts.usd_token.clone(),
+                to: accounts.treasury.clone(),
+                authority: accounts.authority.clone(),
+            },
+        )
+        .with_signer(signer);
+        token::mint_to(cpi_ctx_treasury, treasury_fee);
+        let cpi_ctx_insurance = CpiContext::new(
+            accounts.token_program.clone(),
+            MintTo {
+                mint: accounts.usd_token.clone(),
+                to: accounts.insurance_fund.clone(),
+                authority: accounts.authority.clone(),
+            },
+        )
+        .with_signer(signer);
+        token::mint_to(cpi_ctx_insurance, insurance_fee);
         Ok(())
     }
 }
@@ -340,21 +1569,91 @@ pub struct CreateUserAccount<'info> {
     pub rent: Sysvar<'info, Rent>,
 }
 #[derive(Accounts)]
+pub struct SetDelegate<'info> {
+    #[account(mut, has_one = owner)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+}
+#[derive(Accounts)]
+pub struct GetUserPosition<'info> {
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    pub clock: Sysvar<'info, Clock>,
+}
+#[derive(Accounts)]
+pub struct ClaimVested<'info> {
+    #[account(mut, has_one = owner)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+}
+#[derive(Accounts)]
 pub struct UpdatePrice<'info> {
     pub price_feed_account: CpiAccount<'info, PriceFeed>,
     pub clock: Sysvar<'info, Clock>,
 }
 #[derive(Accounts)]
+pub struct UpdateIndexPrice<'info> {
+    pub asset_address: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+#[derive(Accounts)]
+pub struct Rebalance<'info> {
+    pub asset_address: AccountInfo<'info>,
+    pub price_feed_account: CpiAccount<'info, PriceFeed>,
+    pub clock: Sysvar<'info, Clock>,
+}
+#[derive(Accounts)]
+pub struct Settle<'info> {
+    pub asset_address: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+#[derive(Accounts)]
+pub struct UpdatePrices<'info> {
+    pub clock: Sysvar<'info, Clock>,
+}
+#[derive(Accounts)]
+pub struct AccrueInterest<'info> {
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub mint: AccountInfo<'info>,
+    #[account(mut)]
+    pub treasury: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+impl<'a, 'b, 'c, 'info> From<&AccrueInterest<'info>>
+    for CpiContext<'a, 'b, 'c, 'info, MintTo<'info>>
+{
+    fn from(accounts: &AccrueInterest<'info>) -> CpiContext<'a, 'b, 'c, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: accounts.mint.to_account_info(),
+            to: accounts.treasury.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+#[derive(Accounts)]
+pub struct SetDebtInterestRate<'info> {
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
 pub struct Mint<'info> {
     pub authority: AccountInfo<'info>,
     #[account(mut)]
     pub mint: AccountInfo<'info>,
     #[account(mut)]
-    pub to: AccountInfo<'info>,
+    pub to: CpiAccount<'info, TokenAccount>,
     pub token_program: AccountInfo<'info>,
-    #[account(mut, has_one = owner)]
+    #[account(mut)]
     pub user_account: ProgramAccount<'info, UserAccount>,
     pub clock: Sysvar<'info, Clock>,
+    // Owner or delegate of user account
     #[account(signer)]
     owner: AccountInfo<'info>,
 }
@@ -376,10 +1675,11 @@ pub struct BurnToken<'info> {
     pub mint: AccountInfo<'info>,
     pub token_program: AccountInfo<'info>,
     #[account(mut)]
-    pub user_token_account: AccountInfo<'info>,
-    #[account(mut, has_one = owner)]
+    pub user_token_account: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
     pub user_account: ProgramAccount<'info, UserAccount>,
     pub clock: Sysvar<'info, Clock>,
+    // Owner or delegate of user account
     #[account(signer)]
     owner: AccountInfo<'info>,
 }
@@ -395,6 +1695,103 @@ impl<'a, 'b, 'c, 'info> From<&BurnToken<'info>> for CpiContext<'a, 'b, 'c, 'info
     }
 }
 #[derive(Accounts)]
+pub struct Redeem<'info> {
+    pub authority: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub mint: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_token_account: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub usd_token: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_usd_account: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+}
+impl<'a, 'b, 'c, 'info> From<&Redeem<'info>> for CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+    fn from(accounts: &Redeem<'info>) -> CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: accounts.mint.to_account_info(),
+            to: accounts.user_token_account.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+impl<'a, 'b, 'c, 'info> From<&Redeem<'info>> for CpiContext<'a, 'b, 'c, 'info, MintTo<'info>> {
+    fn from(accounts: &Redeem<'info>) -> CpiContext<'a, 'b, 'c, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: accounts.usd_token.to_account_info(),
+            to: accounts.user_usd_account.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+#[derive(Accounts)]
+pub struct RepayFor<'info> {
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub mint: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+    #[account(mut)]
+    pub payer_token_account: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    pub payer: AccountInfo<'info>,
+}
+impl<'a, 'b, 'c, 'info> From<&RepayFor<'info>> for CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+    fn from(accounts: &RepayFor<'info>) -> CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: accounts.mint.to_account_info(),
+            to: accounts.payer_token_account.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+#[derive(Accounts)]
+pub struct FlashMint<'info> {
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub mint: AccountInfo<'info>,
+    #[account(mut)]
+    pub receiver: CpiAccount<'info, TokenAccount>,
+    pub token_program: AccountInfo<'info>,
+    pub receiver_program: AccountInfo<'info>,
+    #[account(signer)]
+    pub initiator: AccountInfo<'info>,
+}
+impl<'a, 'b, 'c, 'info> From<&FlashMint<'info>> for CpiContext<'a, 'b, 'c, 'info, MintTo<'info>> {
+    fn from(accounts: &FlashMint<'info>) -> CpiContext<'a, 'b, 'c, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: accounts.mint.to_account_info(),
+            to: accounts.receiver.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+impl<'a, 'b, 'c, 'info> From<&FlashMint<'info>> for CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+    fn from(accounts: &FlashMint<'info>) -> CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: accounts.mint.to_account_info(),
+            to: accounts.receiver.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+#[derive(Accounts)]
 pub struct Swap<'info> {
     pub authority: AccountInfo<'info>,
     pub token_program: AccountInfo<'info>,
@@ -403,12 +1800,19 @@ pub struct Swap<'info> {
     #[account(mut)]
     pub token_for: AccountInfo<'info>,
     #[account(mut)]
-    pub user_token_account_in: AccountInfo<'info>,
+    pub user_token_account_in: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_account_for: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub usd_token: AccountInfo<'info>,
+    #[account(mut)]
+    pub treasury: AccountInfo<'info>,
+    #[account(mut)]
+    pub insurance_fund: AccountInfo<'info>,
     #[account(mut)]
-    pub user_token_account_for: AccountInfo<'info>,
-    #[account(mut, has_one = owner)]
     pub user_account: ProgramAccount<'info, UserAccount>,
     pub clock: Sysvar<'info, Clock>,
+    // Owner or delegate of user account
     #[account(signer)]
     owner: AccountInfo<'info>,
 }
@@ -461,6 +1865,14 @@ impl<'a, 'b, 'c, 'info> From<&Withdraw<'info>> for CpiContext<'a, 'b, 'c, 'info,
     }
 }
 #[derive(Accounts)]
+pub struct RequestWithdrawal<'info> {
+    #[account(mut, has_one = owner)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    owner: AccountInfo<'info>,
+}
+#[derive(Accounts)]
 pub struct AddAsset<'info> {
     pub asset_address: AccountInfo<'info>,
     pub feed_address: AccountInfo<'info>,
@@ -468,6 +1880,91 @@ pub struct AddAsset<'info> {
     pub admin: AccountInfo<'info>,
 }
 #[derive(Accounts)]
+pub struct AddIndexAsset<'info> {
+    pub asset_address: AccountInfo<'info>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
+pub struct WithdrawTreasury<'info> {
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub treasury: AccountInfo<'info>,
+    #[account(mut)]
+    pub to: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+impl<'a, 'b, 'c, 'info> From<&WithdrawTreasury<'info>>
+    for CpiContext<'a, 'b, 'c, 'info, Transfer<'info>>
+{
+    fn from(accounts: &WithdrawTreasury<'info>) -> CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: accounts.treasury.to_account_info(),
+            to: accounts.to.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+#[derive(Accounts)]
+pub struct CoverBadDebt<'info> {
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub mint: AccountInfo<'info>,
+    #[account(mut)]
+    pub insurance_fund: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    pub token_program: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+}
+impl<'a, 'b, 'c, 'info> From<&CoverBadDebt<'info>> for CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+    fn from(accounts: &CoverBadDebt<'info>) -> CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: accounts.mint.to_account_info(),
+            to: accounts.insurance_fund.to_account_info(),
+            authority: accounts.authority.to_account_info(),
+        };
+        let cpi_program = accounts.token_program.to_account_info();
+        CpiContext::new(cpi_program, cpi_accounts)
+    }
+}
+#[derive(Accounts)]
+pub struct SetParameter<'info> {
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
+pub struct SetUserMaxDebt<'info> {
+    #[account(mut)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
+pub struct SetAssetParameter<'info> {
+    pub asset_address: AccountInfo<'info>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
+pub struct SetAssetExpiry<'info> {
+    pub asset_address: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
+pub struct DelistAsset<'info> {
+    pub asset_address: AccountInfo<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+#[derive(Accounts)]
 pub struct Deposit<'info> {
     // #[account(signer)]
     // pub test: AccountInfo<'info>,
@@ -475,22 +1972,131 @@ pub struct Deposit<'info> {
     pub user_account: ProgramAccount<'info, UserAccount>,
     pub collateral_account: CpiAccount<'info, TokenAccount>,
 }
+#[derive(Accounts)]
+pub struct CreateVesting<'info> {
+    #[account(mut)]
+    pub user_account: ProgramAccount<'info, UserAccount>,
+    pub collateral_account: CpiAccount<'info, TokenAccount>,
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
 #[account]
+#[derive(Default)]
 pub struct UserAccount {
     pub owner: Pubkey,
     pub shares: u64,
     pub collateral: u64,
+    pub delegate: Pubkey,
+    pub delegate_permissions: u8,
+    pub max_debt: u64, // 0 means no cap
+    pub unbonding_amount: u64,
+    pub unbonding_since: i64,
+    pub vesting: Vec<VestingEntry>, // escrowed collateral, up to MAX_VESTING_ENTRIES
+}
+impl UserAccount {
+    pub const MAX_VESTING_ENTRIES: usize = 8;
+    // Withdrawals can't be delegated
+    pub const PERMISSION_SWAP: u8 = 1;
+    pub const PERMISSION_MINT_BURN: u8 = 2;
+
+    pub fn check_authority(&self, signer: &Pubkey, permission: u8) -> Result<()> {
+        if self.owner.eq(signer) {
+            return Ok(());
+        }
+        if !self.delegate.eq(&Pubkey::default())
+            && self.delegate.eq(signer)
+            && self.delegate_permissions & permission == permission
+        {
+            return Ok(());
+        }
+        Err(ErrorCode::NotOwnerOrDelegate.into())
+    }
+    // Delegate can only send tokens to accounts of the owner
+    pub fn check_destination(&self, signer: &Pubkey, destination_owner: &Pubkey) -> Result<()> {
+        if self.owner.eq(signer) || self.owner.eq(destination_owner) {
+            return Ok(());
+        }
+        Err(ErrorCode::InvalidDestination.into())
+    }
 }
 
+#[derive(PartialEq, Default, Clone)]
+pub struct UserPosition {
+    pub collateral_value: u64,  // in usd
+    pub debt: u64,              // in usd
+    pub pool_share: u64,        // share of global debt | 10^8 => 100%
+    pub collateral_ratio: u64,  // in percent, 0 means no debt
+    pub max_mintable: u64,      // in usd
+    pub max_withdrawable: u64,  // in collateral token
+    pub liquidation_price: u64, // collateral price crossing collateralization level
+}
+#[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Default, Clone)]
+pub struct VestingEntry {
+    pub amount: u64,
+    pub claimed: u64,
+    pub start: i64, // unix timestamp
+    pub end: i64,
+}
 #[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Default, Clone)]
 pub struct Asset {
     pub feed_address: Pubkey,
     pub asset_address: Pubkey,
     pub price: u64,
-    pub last_update: u64,
+    pub confidence: u64,
+    pub last_update: i64, // publish time of price used, unix timestamp
+    pub last_update_slot: u64,
+    pub max_delay: u32, // 0 means InternalState.max_delay is used
     pub supply: u64,
+    pub max_supply: u64, // 0 means no cap
     pub decimals: u8,
+    pub fee: u8, // base swap fee, 0 means InternalState.fee is used
     pub ticker: Vec<u8>,
+    pub kind: u8,
+    // Inverse assets only, prices with oracle offset
+    pub entry_price: u64,
+    pub lower_limit: u64,
+    pub upper_limit: u64,
+    pub frozen: bool, // price stopped at one of limits
+    // Index assets only, up to MAX_CONSTITUENTS
+    pub constituents: Vec<IndexConstituent>,
+    // Leveraged assets only, negative leverage means short
+    pub leverage: i8,
+    pub checkpoint_price: u64,      // asset price at last rebalance
+    pub checkpoint_feed_price: u64, // feed price at last rebalance, 0 before first one
+    pub last_rebalance: i64,
+    pub expiry: i64, // unix timestamp, 0 means asset never expires
+    pub halted: bool,
+    pub market_closed: bool, // copied from price feed on update
+    // Trading hours in seconds since UTC midnight, equal values mean always open
+    pub trading_start: u32,
+    pub trading_end: u32,
+}
+impl Asset {
+    pub const KIND_REGULAR: u8 = 0;
+    pub const KIND_INVERSE: u8 = 1; // price moves opposite to feed price
+    pub const KIND_INDEX: u8 = 2; // weighted sum of constituent feed prices
+    pub const KIND_LEVERAGED: u8 = 3; // leveraged feed returns since last rebalance
+    pub const MAX_CONSTITUENTS: usize = 5;
+    pub const MAX_LEVERAGE: i8 = 3;
+    pub const LEVERAGED_FLOOR_PRICE: u64 = 1; // leveraged asset freezes at this price
+    pub const REBALANCE_PERIOD: i64 = 24 * 60 * 60; // in seconds
+    pub const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+    // Regular asset, other fields are set by kind specific instructions
+    pub fn new(asset_address: Pubkey, feed_address: Pubkey, ticker: Vec<u8>) -> Self {
+        Asset {
+            asset_address,
+            feed_address,
+            ticker,
+            decimals: 8,
+            ..Default::default()
+        }
+    }
+}
+#[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Default, Clone)]
+pub struct IndexConstituent {
+    pub feed_address: Pubkey,
+    pub weight: u64, // units of constituent per index token | 10^4 => 1
 }
 
 #[error]
@@ -515,4 +2121,66 @@ pub enum ErrorCode {
     SyntheticCollateral,
     #[msg("You are not admin of system")]
     Unauthorized,
+    #[msg("Price feed is not owned by oracle program")]
+    InvalidOracleProgram,
+    #[msg("No asset uses this price feed")]
+    FeedNotFound,
+    #[msg("Asset not found")]
+    AssetNotFound,
+    #[msg("Wrong treasury account")]
+    InvalidTreasury,
+    #[msg("Interest has to be accrued first")]
+    InterestNotAccrued,
+    #[msg("Parameter out of range")]
+    ParameterOutOfRange,
+    #[msg("Slippage limit exceeded")]
+    Slippage,
+    #[msg("Route is too long")]
+    RouteTooLong,
+    #[msg("User has no debt")]
+    NoDebt,
+    #[msg("Not enough tokens to burn")]
+    InsufficientBalance,
+    #[msg("Token account is not owned by signer")]
+    InvalidTokenAccount,
+    #[msg("Signer is not owner or permitted delegate")]
+    NotOwnerOrDelegate,
+    #[msg("Debt ceiling crossed")]
+    DebtCeiling,
+    #[msg("Asset supply cap crossed")]
+    SupplyCap,
+    #[msg("User debt cap crossed")]
+    UserDebtCap,
+    #[msg("Operation is paused")]
+    Paused,
+    #[msg("Wrong insurance fund account")]
+    InvalidInsuranceFund,
+    #[msg("User has no bad debt")]
+    NoBadDebt,
+    #[msg("Withdrawal requires unbonding")]
+    UnbondingRequired,
+    #[msg("Unbonding period not finished")]
+    Unbonding,
+    #[msg("Too many vesting entries")]
+    TooManyVestingEntries,
+    #[msg("Asset is frozen")]
+    AssetFrozen,
+    #[msg("Price feeds don't match index constituents")]
+    InvalidConstituents,
+    #[msg("Rebalance period has not passed")]
+    RebalanceTooEarly,
+    #[msg("Asset expired")]
+    AssetExpired,
+    #[msg("Asset has not expired")]
+    NotExpired,
+    #[msg("Asset is not settled")]
+    NotSettled,
+    #[msg("Asset can't be delisted")]
+    AssetNotDelistable,
+    #[msg("Trading of asset is halted")]
+    TradingHalted,
+    #[msg("Delegate can only send tokens to owner")]
+    InvalidDestination,
+    #[msg("Wrong collateral account")]
+    InvalidCollateralAccount,
 }
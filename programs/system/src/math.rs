@@ -5,44 +5,168 @@ use crate::*;
 // Maybe is should be part of Asset ?
 const ACCURACCY: u8 = 8;
 const ORACLE_OFFSET: u8 = 4;
+const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+const MAX_SWAP_FEE: u16 = 300; // 3%
+const WEIGHT_OFFSET: u8 = 4;
 
 // Switch to u128? Reduce decimals for tokens ?
 // At least rust will error during overflows checkmate Solidity
 
 // USD prices have 8 decimal places
+pub fn asset_max_delay(asset: &Asset, max_delay: u32) -> u32 {
+    if asset.max_delay == 0 {
+        return max_delay;
+    }
+    asset.max_delay
+}
+// Index assets have no feed of their own either
+pub fn is_synthetic_usd(asset: &Asset) -> bool {
+    asset.feed_address.eq(&Pubkey::default()) && asset.kind == Asset::KIND_REGULAR
+}
+pub fn is_outdated(asset: &Asset, max_delay: u32, timestamp: i64) -> bool {
+    // Synthetic USD and frozen assets never get outdated
+    if is_synthetic_usd(asset) || asset.frozen {
+        return false;
+    }
+    // Expired assets keep last price recorded before expiry, it has to be fresh at expiry
+    let timestamp = if is_expired(asset, timestamp) {
+        asset.expiry
+    } else {
+        timestamp
+    };
+    timestamp - asset.last_update > asset_max_delay(asset, max_delay) as i64
+}
+// Returns price of inverse asset and if it hit one of limits
+pub fn calculate_inverse_price(asset: &Asset, feed_price: u64) -> (u64, bool) {
+    let double_entry = 2 * asset.entry_price as u128;
+    if double_entry <= feed_price as u128 {
+        return (asset.lower_limit, true);
+    }
+    let price = (double_entry - feed_price as u128) as u64;
+    if price <= asset.lower_limit {
+        return (asset.lower_limit, true);
+    }
+    if price >= asset.upper_limit {
+        return (asset.upper_limit, true);
+    }
+    (price, false)
+}
+// Returns price and confidence of index from constituent prices
+pub fn calculate_index_price(
+    constituents: &Vec<IndexConstituent>,
+    prices: &Vec<u64>,
+    confidences: &Vec<u64>,
+) -> (u64, u64) {
+    let mut price: u128 = 0;
+    let mut confidence: u128 = 0;
+    for (i, constituent) in constituents.iter().enumerate() {
+        price += prices[i] as u128 * constituent.weight as u128;
+        confidence += confidences[i] as u128 * constituent.weight as u128;
+    }
+    let offset = 10u128.pow(WEIGHT_OFFSET.into());
+    ((price / offset) as u64, (confidence / offset) as u64)
+}
+// Applies leveraged feed return since last rebalance, returns price and if it hit the floor
+pub fn calculate_leveraged_price(asset: &Asset, feed_price: u64) -> (u64, bool) {
+    if asset.checkpoint_feed_price == 0 {
+        return (asset.checkpoint_price, false);
+    }
+    let checkpoint_feed_price = asset.checkpoint_feed_price as i128;
+    let value = checkpoint_feed_price
+        + asset.leverage as i128 * (feed_price as i128 - checkpoint_feed_price);
+    if value <= 0 {
+        return (Asset::LEVERAGED_FLOOR_PRICE, true);
+    }
+    let price = (asset.checkpoint_price as i128 * value / checkpoint_feed_price) as u64;
+    if price <= Asset::LEVERAGED_FLOOR_PRICE {
+        return (Asset::LEVERAGED_FLOOR_PRICE, true);
+    }
+    (price, false)
+}
+// Leverage scales relative uncertainty of feed
+pub fn calculate_leveraged_confidence(asset: &Asset, feed_confidence: u64) -> u64 {
+    if asset.checkpoint_feed_price == 0 {
+        return 0;
+    }
+    let confidence =
+        feed_confidence as u128 * asset.leverage.abs() as u128 * asset.checkpoint_price as u128
+            / asset.checkpoint_feed_price as u128;
+    confidence as u64
+}
+// Frozen assets keep their last price
+pub fn set_asset_price(asset: &mut Asset, feed_price: u64, confidence: u64) {
+    if asset.frozen {
+        return;
+    }
+    let confidence = match asset.kind {
+        Asset::KIND_INVERSE => {
+            let (price, frozen) = calculate_inverse_price(asset, feed_price);
+            asset.price = price;
+            asset.frozen = frozen;
+            confidence
+        }
+        Asset::KIND_LEVERAGED => {
+            let (price, frozen) = calculate_leveraged_price(asset, feed_price);
+            asset.price = price;
+            asset.frozen = frozen;
+            calculate_leveraged_confidence(asset, confidence)
+        }
+        _ => {
+            asset.price = feed_price;
+            confidence
+        }
+    };
+    // Price of frozen asset is certain
+    asset.confidence = if asset.frozen { 0 } else { confidence };
+}
+pub fn is_expired(asset: &Asset, timestamp: i64) -> bool {
+    asset.expiry != 0 && timestamp >= asset.expiry
+}
+// Trading hours may wrap around midnight
+pub fn is_trading_halted(asset: &Asset, timestamp: i64) -> bool {
+    if asset.halted || asset.market_closed {
+        return true;
+    }
+    if asset.trading_start == asset.trading_end {
+        return false;
+    }
+    let time_of_day = timestamp.rem_euclid(Asset::SECONDS_PER_DAY as i64) as u32;
+    if asset.trading_start < asset.trading_end {
+        time_of_day < asset.trading_start || time_of_day >= asset.trading_end
+    } else {
+        time_of_day < asset.trading_start && time_of_day >= asset.trading_end
+    }
+}
 pub fn check_feed_update(
     assets: &Vec<Asset>,
     indexA: usize,
     indexB: usize,
     max_delay: u32,
-    slot: u64,
+    timestamp: i64,
 ) -> Result<()> {
     // Check assetA
-    if !assets[indexA].feed_address.eq(&Pubkey::default()) {
-        msg!("checkA {}", slot);
-        if (assets[indexA].last_update + max_delay as u64) < slot {
-            return Err(ErrorCode::OutdatedOracle.into());
-        }
+    if is_outdated(&assets[indexA], max_delay, timestamp) {
+        return Err(ErrorCode::OutdatedOracle.into());
     }
     // Check assetB
-    if !assets[indexB].feed_address.eq(&Pubkey::default()) {
-        msg!("checkB {}", slot);
-        if (assets[indexB].last_update + max_delay as u64) < slot {
-            return Err(ErrorCode::OutdatedOracle.into());
-        }
+    if is_outdated(&assets[indexB], max_delay, timestamp) {
+        return Err(ErrorCode::OutdatedOracle.into());
+    }
+    return Ok(());
+}
+pub fn check_supply_cap(asset: &Asset, minted_amount: u64) -> Result<()> {
+    if asset.max_supply != 0 && asset.supply + minted_amount > asset.max_supply {
+        return Err(ErrorCode::SupplyCap.into());
     }
     return Ok(());
 }
-pub fn calculate_debt(assets: &Vec<Asset>, slot: u64, max_delay: u32) -> Result<u64> {
+pub fn calculate_debt(assets: &Vec<Asset>, timestamp: i64, max_delay: u32) -> Result<u64> {
     let mut debt = 0u128;
     for asset in assets.iter() {
-        if (asset.last_update + max_delay as u64) < slot {
+        if is_outdated(asset, max_delay, timestamp) {
             msg!("last update {}", asset.last_update);
-            msg!("slot {}", slot);
-            if asset.feed_address.eq(&Pubkey::default()) {
-            } else {
-                return Err(ErrorCode::OutdatedOracle.into());
-            }
+            msg!("timestamp {}", timestamp);
+            return Err(ErrorCode::OutdatedOracle.into());
         }
         debt += (asset.price as u128 * asset.supply as u128)
             / 10u128.pow(
@@ -66,7 +190,9 @@ pub fn calculate_max_user_debt_in_usd(
     collateralization_level: u32,
     user_account: &UserAccount,
 ) -> u64 {
-    let user_max_debt = collateral_asset.price as u128 * user_account.collateral as u128
+    // Escrowed collateral counts toward mint capacity
+    let collateral = user_account.collateral + calculate_escrowed(user_account);
+    let user_max_debt = collateral_asset.price as u128 * collateral as u128
         / 10u128.pow(
             (collateral_asset.decimals + ORACLE_OFFSET - ACCURACCY)
                 .try_into()
@@ -76,6 +202,24 @@ pub fn calculate_max_user_debt_in_usd(
         .try_into()
         .unwrap();
 }
+pub fn calculate_vested(entry: &VestingEntry, timestamp: i64) -> u64 {
+    if timestamp <= entry.start {
+        return 0;
+    }
+    if timestamp >= entry.end {
+        return entry.amount;
+    }
+    let vested = entry.amount as u128 * (timestamp - entry.start) as u128
+        / (entry.end - entry.start) as u128;
+    return vested as u64;
+}
+pub fn calculate_escrowed(user_account: &UserAccount) -> u64 {
+    user_account
+        .vesting
+        .iter()
+        .map(|x| x.amount - x.claimed)
+        .sum()
+}
 pub fn calculate_max_withdraw_in_usd(
     max_user_debt_in_usd: &u64,
     user_debt_in_usd: &u64,
@@ -86,6 +230,61 @@ pub fn calculate_max_withdraw_in_usd(
     }
     return ((max_user_debt_in_usd - user_debt_in_usd) * *collateralization_level as u64) / 100;
 }
+pub fn calculate_max_withdraw_in_token(collateral_asset: &Asset, max_withdraw_in_usd: u64) -> u64 {
+    if collateral_asset.price == 0 {
+        return 0;
+    }
+    let decimals_offset =
+        10u128.pow((collateral_asset.decimals + ORACLE_OFFSET - ACCURACCY).into());
+    (max_withdraw_in_usd as u128 * decimals_offset / collateral_asset.price as u128) as u64
+}
+// Liquidation price assumes prices of synthetics stay the same
+pub fn calculate_user_position(
+    collateral_asset: &Asset,
+    collateralization_level: u32,
+    user_account: &UserAccount,
+    debt: u64,
+    debt_shares: u64,
+) -> UserPosition {
+    let user_debt = calculate_user_debt_in_usd(user_account, debt, debt_shares);
+    let collateral_value = calculate_max_user_debt_in_usd(collateral_asset, 100, user_account);
+    let max_user_debt =
+        calculate_max_user_debt_in_usd(collateral_asset, collateralization_level, user_account);
+    let max_withdraw_in_usd =
+        calculate_max_withdraw_in_usd(&max_user_debt, &user_debt, &collateralization_level);
+    let decimals_offset = 10u128.pow(
+        (collateral_asset.decimals + ORACLE_OFFSET - ACCURACCY)
+            .try_into()
+            .unwrap(),
+    );
+    let max_withdrawable = std::cmp::min(
+        calculate_max_withdraw_in_token(collateral_asset, max_withdraw_in_usd),
+        user_account.collateral,
+    );
+    let collateral = user_account.collateral + calculate_escrowed(user_account);
+    let liquidation_price = match collateral {
+        0 => 0,
+        collateral => {
+            user_debt as u128 * collateralization_level as u128 * decimals_offset
+                / (100 * collateral as u128)
+        }
+    };
+    UserPosition {
+        collateral_value,
+        debt: user_debt,
+        pool_share: match debt {
+            0 => 0,
+            debt => (user_debt as u128 * 10u128.pow(ACCURACCY.into()) / debt as u128) as u64,
+        },
+        collateral_ratio: match user_debt {
+            0 => 0,
+            user_debt => (collateral_value as u128 * 100 / user_debt as u128) as u64,
+        },
+        max_mintable: max_user_debt.saturating_sub(user_debt),
+        max_withdrawable,
+        liquidation_price: liquidation_price as u64,
+    }
+}
 pub fn calculate_amount_mint_in_usd(mint_asset: &Asset, amount: u64) -> u64 {
     let mint_amount_in_usd = mint_asset.price as u128 * amount as u128
         / 10u128.pow((mint_asset.decimals + ORACLE_OFFSET - ACCURACCY).into());
@@ -115,12 +314,51 @@ pub fn calculate_max_burned_in_token(asset: &Asset, user_debt: &u64) -> u64 {
         *user_debt as u128 * 10u128.pow(ORACLE_OFFSET.into()) / asset.price as u128;
     return burned_amount_token as u64;
 }
+// Returns burned amount, burned shares and repaid debt in usd
+pub fn calculate_burn(
+    asset: &Asset,
+    user_debt: &u64,
+    user_shares: &u64,
+    amount: &u64,
+) -> (u64, u64, u64) {
+    let burned_shares = calculate_burned_shares(asset, user_debt, user_shares, amount);
+    if burned_shares > *user_shares {
+        let burned_amount = calculate_max_burned_in_token(asset, user_debt);
+        return (burned_amount, *user_shares, *user_debt);
+    }
+    let debt_repaid = calculate_amount_mint_in_usd(asset, *amount);
+    return (*amount, burned_shares, debt_repaid);
+}
 
+// Fee grows with oracle confidence width and with asset share in global debt
+pub fn calculate_swap_fee(asset_in: &Asset, asset_for: &Asset, base_fee: u8, debt: u64) -> u16 {
+    let mut fee = if asset_for.fee == 0 {
+        base_fee as u128
+    } else {
+        asset_for.fee as u128
+    };
+    // Confidence width of both legs in basis points
+    for asset in [asset_in, asset_for].iter() {
+        if asset.price > 0 {
+            fee += asset.confidence as u128 * 10000 / asset.price as u128;
+        }
+    }
+    // Synthetic USD has no feed so there is no skew to charge for
+    // Every 1% share of global debt adds 0.01% fee
+    if debt > 0 && !is_synthetic_usd(asset_for) {
+        let asset_debt = calculate_amount_mint_in_usd(asset_for, asset_for.supply);
+        fee += asset_debt as u128 * 100 / debt as u128;
+    }
+    if fee > MAX_SWAP_FEE as u128 {
+        return MAX_SWAP_FEE;
+    }
+    return fee as u16;
+}
 pub fn calculate_swap_out_amount(
     asset_in: &Asset,
     asset_for: &Asset,
     amount: &u64,
-    fee: &u8, // in range from 0-99 | 30/10000 => 0.3% fee
+    fee: &u16, // in basis points | 30/10000 => 0.3% fee
 ) -> u64 {
     // Assume same amount of decimals
     // TODO: Fix that for future
@@ -128,6 +366,68 @@ pub fn calculate_swap_out_amount(
     let amount = amount_before_fee - (amount_before_fee * *fee as u128 / 10000);
     return amount as u64;
 }
+// Prices of consecutive hops are chained, fee is charged once
+pub fn calculate_route_out_amount(route: &Vec<&Asset>, amount: &u64, fee: &u16) -> u64 {
+    let mut amount_for = *amount;
+    for hop in route.windows(2) {
+        amount_for = calculate_swap_out_amount(hop[0], hop[1], &amount_for, &0);
+    }
+    let amount = amount_for as u128 - (amount_for as u128 * *fee as u128 / 10000);
+    return amount as u64;
+}
+// Inverse of calculate_swap_out_amount, rounds in favor of the system
+pub fn calculate_swap_in_amount(
+    asset_in: &Asset,
+    asset_for: &Asset,
+    amount_for: &u64,
+    fee: &u16, // in basis points | 30/10000 => 0.3% fee
+) -> u64 {
+    let amount_before_fee = div_up(*amount_for as u128 * 10000, 10000 - *fee as u128);
+    let amount = div_up(
+        amount_before_fee * asset_for.price as u128,
+        asset_in.price as u128,
+    );
+    return amount as u64;
+}
+pub fn div_up(a: u128, b: u128) -> u128 {
+    return (a + b - 1) / b;
+}
+// Part of swap fee minted as synthetic USD (to treasury or insurance fund)
+pub fn calculate_fee_share(
+    asset_in: &Asset,
+    amount: &u64,
+    fee: &u16,
+    fee_share: &u8, // in range from 0-100 | 20 => 20% of fee
+) -> u64 {
+    let amount_in_usd = calculate_amount_mint_in_usd(asset_in, *amount);
+    let fee_in_usd = amount_in_usd as u128 * *fee as u128 / 10000;
+    return (fee_in_usd * *fee_share as u128 / 100) as u64;
+}
+// Returns debt exceeding collateral value
+pub fn calculate_bad_debt(
+    collateral_asset: &Asset,
+    user_account: &UserAccount,
+    user_debt: u64,
+) -> u64 {
+    let collateral_value = calculate_max_user_debt_in_usd(collateral_asset, 100, user_account);
+    if user_debt <= collateral_value {
+        return 0;
+    }
+    return user_debt - collateral_value;
+}
+// Rounded up so flash mint is never free
+pub fn calculate_flash_mint_fee(amount: u64, fee: u16) -> u64 {
+    return div_up(amount as u128 * fee as u128, 10000) as u64;
+}
+// interest_rate is yearly in basis points | 100 => 1%
+pub fn calculate_interest(debt: u64, interest_rate: u16, elapsed: i64) -> u64 {
+    if elapsed <= 0 {
+        return 0;
+    }
+    let interest =
+        debt as u128 * interest_rate as u128 * elapsed as u128 / (10000 * SECONDS_PER_YEAR);
+    return interest as u64;
+}
 #[cfg(test)]
 mod tests {
     use std::ops::Div;
@@ -135,14 +435,15 @@ mod tests {
     use super::*;
     #[test]
     fn test_calculate_debt_success() {
-        let slot = 100;
+        let timestamp = 100;
         let accuracy = 8;
         let asset_1 = Asset {
             // oracle offset set as 4
             price: 10 * 10u64.pow(ORACLE_OFFSET.into()),
             supply: 100 * 10u64.pow(8),
-            last_update: slot - 10,
+            last_update: timestamp - 10,
             decimals: 8,
+            feed_address: Pubkey::new_unique(),
             ..Default::default()
         };
         // debt 1000
@@ -151,11 +452,12 @@ mod tests {
             supply: 200 * 10u64.pow(8),
             last_update: 100,
             decimals: 8,
+            feed_address: Pubkey::new_unique(),
             ..Default::default()
         };
         // debt 2400
         let assets: Vec<Asset> = vec![asset_1, asset_2];
-        let result = calculate_debt(&assets, slot, 100);
+        let result = calculate_debt(&assets, timestamp, 10);
         match result {
             Ok(debt) => assert_eq!(debt, 3400 * 10u64.pow(accuracy)),
             Err(_) => assert!(false, "Shouldn't check"),
@@ -163,11 +465,11 @@ mod tests {
     }
     #[test]
     fn test_calculate_debt_error() {
-        let slot = 100;
+        let timestamp = 100;
         let asset_1 = Asset {
             price: 10 * 10u64.pow(ORACLE_OFFSET.into()),
             supply: 100 * 10u64.pow(8),
-            last_update: slot - 10,
+            last_update: timestamp - 10,
             decimals: 8,
             feed_address: Pubkey::new_unique(),
             ..Default::default()
@@ -182,11 +484,102 @@ mod tests {
         };
         // debt 2400
         let assets: Vec<Asset> = vec![asset_1, asset_2];
-        let result = calculate_debt(&assets, slot, 0);
+        let result = calculate_debt(&assets, timestamp, 9);
         // println!("{:?}", result);
         assert!(result.is_err());
     }
     #[test]
+    fn test_calculate_debt_synthetic_usd_never_outdated() {
+        let timestamp = 100;
+        let usd = Asset {
+            price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
+            supply: 100 * 10u64.pow(8),
+            last_update: std::i64::MAX,
+            decimals: 8,
+            ..Default::default()
+        };
+        let assets: Vec<Asset> = vec![usd];
+        let result = calculate_debt(&assets, timestamp, 0);
+        assert_eq!(result.unwrap(), 100 * 10u64.pow(ACCURACCY.into()));
+    }
+    #[test]
+    fn test_check_feed_update() {
+        let timestamp = 1000;
+        let usd = Asset {
+            last_update: std::i64::MAX,
+            ..Default::default()
+        };
+        let fresh = Asset {
+            last_update: timestamp - 60,
+            feed_address: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        let outdated = Asset {
+            last_update: timestamp - 61,
+            feed_address: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        let assets: Vec<Asset> = vec![usd, fresh, outdated];
+        // Up to date
+        assert!(check_feed_update(&assets, 0, 1, 60, timestamp).is_ok());
+        assert!(check_feed_update(&assets, 1, 0, 60, timestamp).is_ok());
+        // Outdated
+        assert!(check_feed_update(&assets, 0, 2, 60, timestamp).is_err());
+        assert!(check_feed_update(&assets, 2, 1, 60, timestamp).is_err());
+        // Bigger delay
+        assert!(check_feed_update(&assets, 1, 2, 61, timestamp).is_ok());
+    }
+    #[test]
+    fn test_per_asset_max_delay() {
+        let timestamp = 1000;
+        let crypto = Asset {
+            price: 10 * 10u64.pow(ORACLE_OFFSET.into()),
+            supply: 100 * 10u64.pow(8),
+            last_update: timestamp - 100,
+            decimals: 8,
+            feed_address: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        let fx = Asset {
+            price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
+            supply: 100 * 10u64.pow(8),
+            last_update: timestamp - 3600,
+            max_delay: 3600,
+            decimals: 8,
+            feed_address: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        // Fallback to global delay
+        assert_eq!(asset_max_delay(&crypto, 100), 100);
+        // Asset delay overrides global one
+        assert_eq!(asset_max_delay(&fx, 100), 3600);
+
+        let assets: Vec<Asset> = vec![crypto, fx];
+        assert!(check_feed_update(&assets, 0, 1, 100, timestamp).is_ok());
+        assert!(calculate_debt(&assets, timestamp, 100).is_ok());
+        // Crypto asset gets outdated, fx is still fine
+        assert!(check_feed_update(&assets, 0, 1, 99, timestamp).is_err());
+        assert!(calculate_debt(&assets, timestamp, 99).is_err());
+        assert!(!is_outdated(&assets[1], 99, timestamp));
+        // Fx asset gets outdated
+        assert!(is_outdated(&assets[1], 99, timestamp + 1));
+    }
+    #[test]
+    fn test_check_supply_cap() {
+        let asset = Asset {
+            supply: 100,
+            ..Default::default()
+        };
+        // No cap
+        assert!(check_supply_cap(&asset, std::u64::MAX - 100).is_ok());
+        let capped = Asset {
+            max_supply: 150,
+            ..asset.clone()
+        };
+        assert!(check_supply_cap(&capped, 50).is_ok());
+        assert!(check_supply_cap(&capped, 51).is_err());
+    }
+    #[test]
     fn test_calculate_user_debt_in_usd() {
         let debt = 1000;
         let debt_shares = 1000;
@@ -195,6 +588,7 @@ mod tests {
             collateral: 100,
             shares: 10,
             owner: Pubkey::default(),
+            ..Default::default()
         };
         let user_debt = calculate_user_debt_in_usd(&user_account, debt, debt_shares);
         assert_eq!(user_debt, debt * user_account.shares / debt_shares);
@@ -203,6 +597,7 @@ mod tests {
             collateral: 100,
             shares: 0,
             owner: Pubkey::default(),
+            ..Default::default()
         };
         let user_debt_zero_shares =
             calculate_user_debt_in_usd(&user_account_zero_shares, debt, debt_shares);
@@ -216,6 +611,7 @@ mod tests {
             collateral: 10 * 10u64.pow(8),
             shares: 10,
             owner: Pubkey::default(),
+            ..Default::default()
         };
         let collateral_asset = Asset {
             price: 12 * 10u64.pow(ORACLE_OFFSET.into()),
@@ -284,6 +680,29 @@ mod tests {
         assert_eq!(max_withdraw_in_usd, 50);
     }
     #[test]
+    fn test_calculate_max_withdraw_in_token() {
+        let collateral_asset = Asset {
+            price: 2 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        // 50 usd of collateral priced 2 usd
+        let max_withdraw_in_usd = 50 * 10u64.pow(ACCURACCY.into());
+        assert_eq!(
+            calculate_max_withdraw_in_token(&collateral_asset, max_withdraw_in_usd),
+            25 * 10u64.pow(8)
+        );
+        // Collateral without price can't be withdrawn
+        let no_price = Asset {
+            price: 0,
+            ..collateral_asset.clone()
+        };
+        assert_eq!(
+            calculate_max_withdraw_in_token(&no_price, max_withdraw_in_usd),
+            0
+        );
+    }
+    #[test]
     fn test_calculate_burned_shares() {
         let user_debt_in_usd = 100 * 10u64.pow(ACCURACCY.into());
         let asset = Asset {
@@ -313,9 +732,34 @@ mod tests {
         assert_eq!(amount_to_burn, 50 * 10u64.pow(asset.decimals.into()));
     }
     #[test]
+    fn test_calculate_burn() {
+        let user_debt_in_usd = 100 * 10u64.pow(ACCURACCY.into());
+        let user_shares = 10u64.pow(8u32);
+        let asset = Asset {
+            price: 2 * 10u64.pow(ORACLE_OFFSET.into()),
+            last_update: 100,
+            decimals: 8,
+            ..Default::default()
+        };
+        // Partial burn, 20 tokens for 2 usd each repay 40% of debt
+        let amount = 20 * 10u64.pow(asset.decimals as u32);
+        let (burned_amount, burned_shares, debt_repaid) =
+            calculate_burn(&asset, &user_debt_in_usd, &user_shares, &amount);
+        assert_eq!(burned_amount, amount);
+        assert_eq!(burned_shares, user_shares * 2 / 5);
+        assert_eq!(debt_repaid, 40 * 10u64.pow(ACCURACCY.into()));
+        // Burn over debt only burns 50 tokens
+        let amount = 80 * 10u64.pow(asset.decimals as u32);
+        let (burned_amount, burned_shares, debt_repaid) =
+            calculate_burn(&asset, &user_debt_in_usd, &user_shares, &amount);
+        assert_eq!(burned_amount, 50 * 10u64.pow(asset.decimals as u32));
+        assert_eq!(burned_shares, user_shares);
+        assert_eq!(debt_repaid, user_debt_in_usd);
+    }
+    #[test]
     fn test_calculate_swap_out_amount() {
         let amount_in = 1000 * 10u64.pow(ACCURACCY.into());
-        let fee = 30u8;
+        let fee = 30u16;
         let asset_in = Asset {
             price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
             last_update: 100,
@@ -341,4 +785,512 @@ mod tests {
         let amount = calculate_swap_out_amount(&asset_in, &asset_for_2, &amount_in, &fee);
         assert_eq!(amount, 4985 * 10u64.pow(ACCURACCY.into()) / 10);
     }
+    #[test]
+    fn test_calculate_route_out_amount() {
+        let amount_in = 1000 * 10u64.pow(ACCURACCY.into());
+        let fee = 30u16;
+        let usd = Asset {
+            price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        let asset_2 = Asset {
+            price: 2 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        let asset_4 = Asset {
+            price: 4 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        // Direct route is the same as swap
+        let amount = calculate_route_out_amount(&vec![&usd, &asset_2], &amount_in, &fee);
+        assert_eq!(
+            amount,
+            calculate_swap_out_amount(&usd, &asset_2, &amount_in, &fee)
+        );
+        // Intermediate prices cancel out and fee is charged once
+        let amount = calculate_route_out_amount(&vec![&usd, &asset_4, &asset_2], &amount_in, &fee);
+        assert_eq!(amount, 4985 * 10u64.pow(ACCURACCY.into()) / 10);
+        // Round trip
+        let amount =
+            calculate_route_out_amount(&vec![&usd, &asset_2, &asset_4, &usd], &amount_in, &fee);
+        assert_eq!(amount, 997 * 10u64.pow(ACCURACCY.into()));
+    }
+    #[test]
+    fn test_calculate_swap_in_amount() {
+        let fee = 30u16;
+        let asset_in = Asset {
+            price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        let asset_for = Asset {
+            price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        let asset_for_2 = Asset {
+            price: 2 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        // Test on tokens with same price
+        let amount_for = 997 * 10u64.pow(ACCURACCY.into());
+        let amount = calculate_swap_in_amount(&asset_in, &asset_for, &amount_for, &fee);
+        assert_eq!(amount, 1000 * 10u64.pow(ACCURACCY.into()));
+        // Test on tokens with different price
+        let amount_for = 4985 * 10u64.pow(ACCURACCY.into()) / 10;
+        let amount = calculate_swap_in_amount(&asset_in, &asset_for_2, &amount_for, &fee);
+        assert_eq!(amount, 1000 * 10u64.pow(ACCURACCY.into()));
+        // Swapping calculated amount always gives at least requested amount
+        for amount_for in [1u64, 7, 333, 12345, 99999999].iter() {
+            let amount = calculate_swap_in_amount(&asset_in, &asset_for_2, amount_for, &fee);
+            let amount_out = calculate_swap_out_amount(&asset_in, &asset_for_2, &amount, &fee);
+            assert!(amount_out >= *amount_for);
+        }
+    }
+    #[test]
+    fn test_div_up() {
+        assert_eq!(div_up(10, 5), 2);
+        assert_eq!(div_up(11, 5), 3);
+        assert_eq!(div_up(0, 5), 0);
+    }
+    #[test]
+    fn test_calculate_swap_fee() {
+        let debt = 10000 * 10u64.pow(ACCURACCY.into());
+        let usd = Asset {
+            price: 1 * 10u64.pow(ORACLE_OFFSET.into()),
+            supply: 5000 * 10u64.pow(8),
+            decimals: 8,
+            ..Default::default()
+        };
+        // Synthetic USD only pays base fee
+        assert_eq!(calculate_swap_fee(&usd, &usd, 30, debt), 30);
+        let asset = Asset {
+            price: 10 * 10u64.pow(ORACLE_OFFSET.into()),
+            supply: 0,
+            decimals: 8,
+            feed_address: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        // No confidence and no skew
+        assert_eq!(calculate_swap_fee(&usd, &asset, 30, debt), 30);
+        // Asset base fee overrides global one
+        let asset_with_fee = Asset {
+            fee: 50,
+            ..asset.clone()
+        };
+        assert_eq!(calculate_swap_fee(&usd, &asset_with_fee, 30, debt), 50);
+        // Confidence 0.02 on 10 usd price => 0.2% width
+        let volatile = Asset {
+            confidence: 2 * 10u64.pow(ORACLE_OFFSET.into()) / 100,
+            ..asset.clone()
+        };
+        assert_eq!(calculate_swap_fee(&usd, &volatile, 30, debt), 50);
+        // Input leg confidence is charged too
+        assert_eq!(calculate_swap_fee(&volatile, &usd, 30, debt), 50);
+        assert_eq!(calculate_swap_fee(&volatile, &volatile, 30, debt), 70);
+        // 500 tokens * 10 usd => 50% of debt
+        let skewed = Asset {
+            supply: 500 * 10u64.pow(8),
+            ..asset.clone()
+        };
+        assert_eq!(calculate_swap_fee(&usd, &skewed, 30, debt), 80);
+        // Capped
+        let capped = Asset {
+            confidence: 10u64.pow(ORACLE_OFFSET.into()),
+            ..skewed.clone()
+        };
+        assert_eq!(calculate_swap_fee(&usd, &capped, 30, debt), MAX_SWAP_FEE);
+        // Index asset has no feed but still pays for skew
+        let index = Asset {
+            feed_address: Pubkey::default(),
+            kind: Asset::KIND_INDEX,
+            ..skewed.clone()
+        };
+        assert_eq!(calculate_swap_fee(&usd, &index, 30, debt), 80);
+    }
+    #[test]
+    fn test_calculate_fee_share() {
+        let amount_in = 1000 * 10u64.pow(ACCURACCY.into());
+        let fee = 30u16;
+        let asset_in = Asset {
+            price: 2 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        // 2000 usd swapped with 0.3% fee => 6 usd fee, 20% of it is 1.2 usd
+        let treasury_fee = calculate_fee_share(&asset_in, &amount_in, &fee, &20);
+        assert_eq!(treasury_fee, 12 * 10u64.pow(ACCURACCY.into()) / 10);
+        // Whole fee
+        let treasury_fee = calculate_fee_share(&asset_in, &amount_in, &fee, &100);
+        assert_eq!(treasury_fee, 6 * 10u64.pow(ACCURACCY.into()));
+        // No fee for treasury
+        let treasury_fee = calculate_fee_share(&asset_in, &amount_in, &fee, &0);
+        assert_eq!(treasury_fee, 0);
+    }
+    #[test]
+    fn test_set_asset_price() {
+        let mut regular = Asset {
+            kind: Asset::KIND_REGULAR,
+            ..Default::default()
+        };
+        set_asset_price(&mut regular, 100, 1);
+        assert_eq!(regular.price, 100);
+        assert_eq!(regular.confidence, 1);
+        // Inverse entered at 100 with limits 50 - 150
+        let mut inverse = Asset {
+            kind: Asset::KIND_INVERSE,
+            entry_price: 100,
+            lower_limit: 50,
+            upper_limit: 150,
+            ..Default::default()
+        };
+        set_asset_price(&mut inverse, 120, 1);
+        assert_eq!(inverse.price, 80);
+        assert_eq!(inverse.frozen, false);
+        set_asset_price(&mut inverse, 70, 1);
+        assert_eq!(inverse.price, 130);
+        // Lower limit freezes asset
+        set_asset_price(&mut inverse, 160, 2);
+        assert_eq!(inverse.price, 50);
+        assert_eq!(inverse.frozen, true);
+        assert_eq!(inverse.confidence, 0);
+        // Frozen asset keeps price
+        set_asset_price(&mut inverse, 100, 1);
+        assert_eq!(inverse.price, 50);
+        assert_eq!(is_outdated(&inverse, 10, 1000), false);
+    }
+    #[test]
+    fn test_calculate_index_price() {
+        let constituents = vec![
+            IndexConstituent {
+                feed_address: Pubkey::default(),
+                weight: 2 * 10u64.pow(WEIGHT_OFFSET.into()),
+            },
+            IndexConstituent {
+                feed_address: Pubkey::default(),
+                weight: 10u64.pow(WEIGHT_OFFSET.into()) / 2,
+            },
+        ];
+        // 2 * 10 $ + 0.5 * 40 $ => 40 $
+        let prices = vec![
+            10 * 10u64.pow(ORACLE_OFFSET.into()),
+            40 * 10u64.pow(ORACLE_OFFSET.into()),
+        ];
+        let confidences = vec![10, 100];
+        assert_eq!(
+            calculate_index_price(&constituents, &prices, &confidences),
+            (40 * 10u64.pow(ORACLE_OFFSET.into()), 70)
+        );
+    }
+    #[test]
+    fn test_index_outdated() {
+        let index = Asset {
+            kind: Asset::KIND_INDEX,
+            last_update: 100,
+            ..Default::default()
+        };
+        // Index has no feed of its own but still gets outdated
+        assert_eq!(is_outdated(&index, 10, 105), false);
+        assert_eq!(is_outdated(&index, 10, 111), true);
+    }
+    #[test]
+    fn test_calculate_leveraged_price() {
+        let long = Asset {
+            kind: Asset::KIND_LEVERAGED,
+            leverage: 3,
+            checkpoint_price: 100,
+            checkpoint_feed_price: 1000,
+            ..Default::default()
+        };
+        // +10% feed => +30%
+        assert_eq!(calculate_leveraged_price(&long, 1100), (130, false));
+        // -10% feed => -30%
+        assert_eq!(calculate_leveraged_price(&long, 900), (70, false));
+        // -40% feed => frozen at floor instead of going below zero
+        assert_eq!(
+            calculate_leveraged_price(&long, 600),
+            (Asset::LEVERAGED_FLOOR_PRICE, true)
+        );
+        // -33% feed => 1% of price left, at floor
+        assert_eq!(
+            calculate_leveraged_price(&long, 670),
+            (Asset::LEVERAGED_FLOOR_PRICE, true)
+        );
+        let short = Asset {
+            leverage: -2,
+            ..long.clone()
+        };
+        assert_eq!(calculate_leveraged_price(&short, 1100), (80, false));
+        assert_eq!(calculate_leveraged_price(&short, 900), (120, false));
+        assert_eq!(
+            calculate_leveraged_price(&short, 1500),
+            (Asset::LEVERAGED_FLOOR_PRICE, true)
+        );
+        // Before first rebalance
+        let new = Asset {
+            checkpoint_feed_price: 0,
+            ..long.clone()
+        };
+        assert_eq!(calculate_leveraged_price(&new, 1100), (100, false));
+    }
+    #[test]
+    fn test_set_leveraged_asset_price() {
+        let mut asset = Asset {
+            kind: Asset::KIND_LEVERAGED,
+            leverage: 2,
+            checkpoint_price: 100,
+            checkpoint_feed_price: 1000,
+            ..Default::default()
+        };
+        set_asset_price(&mut asset, 1050, 10);
+        assert_eq!(asset.price, 110);
+        // 1% of feed price => 2% of checkpoint price
+        assert_eq!(asset.confidence, 2);
+        // Asset at floor is frozen and keeps its price
+        set_asset_price(&mut asset, 500, 10);
+        assert_eq!(asset.price, Asset::LEVERAGED_FLOOR_PRICE);
+        assert!(asset.frozen);
+        assert_eq!(asset.confidence, 0);
+        set_asset_price(&mut asset, 1000, 10);
+        assert_eq!(asset.price, Asset::LEVERAGED_FLOOR_PRICE);
+    }
+    #[test]
+    fn test_is_expired() {
+        let asset = Asset {
+            expiry: 100,
+            ..Default::default()
+        };
+        assert_eq!(is_expired(&asset, 99), false);
+        assert_eq!(is_expired(&asset, 100), true);
+        // 0 means asset never expires
+        let perpetual = Asset {
+            expiry: 0,
+            ..Default::default()
+        };
+        assert_eq!(is_expired(&perpetual, std::i64::MAX), false);
+        // Price of expired asset is checked at expiry
+        let stale = Asset {
+            feed_address: Pubkey::new_unique(),
+            last_update: 50,
+            ..asset.clone()
+        };
+        assert_eq!(is_outdated(&stale, 10, 99), true);
+        assert_eq!(is_outdated(&stale, 10, 1000), true);
+        let fresh = Asset {
+            last_update: 95,
+            ..stale.clone()
+        };
+        assert_eq!(is_outdated(&fresh, 10, 99), false);
+        assert_eq!(is_outdated(&fresh, 10, 1000), false);
+    }
+    #[test]
+    fn test_is_trading_halted() {
+        let hour = 60 * 60;
+        let day = 24 * hour as i64;
+        let always_open = Asset::default();
+        assert_eq!(is_trading_halted(&always_open, 0), false);
+        let halted = Asset {
+            halted: true,
+            ..Default::default()
+        };
+        assert_eq!(is_trading_halted(&halted, 0), true);
+        let market_closed = Asset {
+            market_closed: true,
+            ..Default::default()
+        };
+        assert_eq!(is_trading_halted(&market_closed, 0), true);
+        // Open 14:30 - 21:00 UTC
+        let equity = Asset {
+            trading_start: 14 * hour + 30 * 60,
+            trading_end: 21 * hour,
+            ..Default::default()
+        };
+        assert_eq!(
+            is_trading_halted(&equity, 10 * day + 14 * hour as i64),
+            true
+        );
+        assert_eq!(
+            is_trading_halted(&equity, 10 * day + 15 * hour as i64),
+            false
+        );
+        assert_eq!(
+            is_trading_halted(&equity, 10 * day + 21 * hour as i64),
+            true
+        );
+        // Open 22:00 - 06:00 UTC
+        let overnight = Asset {
+            trading_start: 22 * hour,
+            trading_end: 6 * hour,
+            ..Default::default()
+        };
+        assert_eq!(
+            is_trading_halted(&overnight, 10 * day + 23 * hour as i64),
+            false
+        );
+        assert_eq!(
+            is_trading_halted(&overnight, 10 * day + 2 * hour as i64),
+            false
+        );
+        assert_eq!(
+            is_trading_halted(&overnight, 10 * day + 12 * hour as i64),
+            true
+        );
+    }
+    #[test]
+    fn test_calculate_inverse_price() {
+        let asset = Asset {
+            kind: Asset::KIND_INVERSE,
+            entry_price: 100,
+            lower_limit: 50,
+            upper_limit: 150,
+            ..Default::default()
+        };
+        assert_eq!(calculate_inverse_price(&asset, 100), (100, false));
+        assert_eq!(calculate_inverse_price(&asset, 149), (51, false));
+        assert_eq!(calculate_inverse_price(&asset, 150), (50, true));
+        assert_eq!(calculate_inverse_price(&asset, 300), (50, true));
+        assert_eq!(calculate_inverse_price(&asset, 51), (149, false));
+        assert_eq!(calculate_inverse_price(&asset, 50), (150, true));
+        assert_eq!(calculate_inverse_price(&asset, 0), (150, true));
+    }
+    #[test]
+    fn test_calculate_user_position() {
+        let collateral_asset = Asset {
+            price: 12 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        let user_account = UserAccount {
+            collateral: 10 * 10u64.pow(8),
+            shares: 10,
+            ..Default::default()
+        };
+        // 1% of 1000 $ debt
+        let debt = 1000 * 10u64.pow(ACCURACCY.into());
+        let position = calculate_user_position(&collateral_asset, 500, &user_account, debt, 1000);
+        assert_eq!(position.collateral_value, 120 * 10u64.pow(ACCURACCY.into()));
+        assert_eq!(position.debt, 10 * 10u64.pow(ACCURACCY.into()));
+        assert_eq!(position.pool_share, 10u64.pow(ACCURACCY.into()) / 100);
+        assert_eq!(position.collateral_ratio, 1200);
+        // 24 $ max debt
+        assert_eq!(position.max_mintable, 14 * 10u64.pow(ACCURACCY.into()));
+        // 70 $ of collateral at 12 $ each
+        assert_eq!(position.max_withdrawable, 583333333);
+        // 10 $ debt needs 50 $ of collateral
+        assert_eq!(
+            position.liquidation_price,
+            5 * 10u64.pow(ORACLE_OFFSET.into())
+        );
+        // No debt
+        let position = calculate_user_position(&collateral_asset, 500, &user_account, 0, 0);
+        assert_eq!(position.debt, 0);
+        assert_eq!(position.pool_share, 0);
+        assert_eq!(position.collateral_ratio, 0);
+        assert_eq!(position.liquidation_price, 0);
+        assert_eq!(position.max_withdrawable, user_account.collateral);
+    }
+    #[test]
+    fn test_calculate_vested() {
+        let entry = VestingEntry {
+            amount: 1000,
+            claimed: 0,
+            start: 100,
+            end: 200,
+        };
+        assert_eq!(calculate_vested(&entry, 50), 0);
+        assert_eq!(calculate_vested(&entry, 100), 0);
+        assert_eq!(calculate_vested(&entry, 125), 250);
+        assert_eq!(calculate_vested(&entry, 200), 1000);
+        assert_eq!(calculate_vested(&entry, 300), 1000);
+    }
+    #[test]
+    fn test_calculate_max_user_debt_with_escrow() {
+        let collateral_asset = Asset {
+            price: 12 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        let user_account = UserAccount {
+            collateral: 5 * 10u64.pow(8),
+            vesting: vec![VestingEntry {
+                amount: 10 * 10u64.pow(8),
+                claimed: 5 * 10u64.pow(8),
+                start: 0,
+                end: 100,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(calculate_escrowed(&user_account), 5 * 10u64.pow(8));
+        // 5 free and 5 escrowed tokens per 12 $ each => 120
+        // collateralization_level 1/5 means 120*1/5 => 24 * decimals
+        assert_eq!(
+            calculate_max_user_debt_in_usd(&collateral_asset, 500, &user_account),
+            24 * 10u64.pow(ACCURACCY.into())
+        );
+    }
+    #[test]
+    fn test_calculate_bad_debt() {
+        let user_account = UserAccount {
+            collateral: 10 * 10u64.pow(8),
+            shares: 1000,
+            ..Default::default()
+        };
+        let collateral_asset = Asset {
+            price: 12 * 10u64.pow(ORACLE_OFFSET.into()),
+            decimals: 8,
+            ..Default::default()
+        };
+        // Collateral worth 120 $
+        let collateral_value = 120 * 10u64.pow(ACCURACCY.into());
+        assert_eq!(
+            calculate_bad_debt(&collateral_asset, &user_account, collateral_value),
+            0
+        );
+        // Debt 150 $ => 30 $ shortfall
+        let user_debt = 150 * 10u64.pow(ACCURACCY.into());
+        assert_eq!(
+            calculate_bad_debt(&collateral_asset, &user_account, user_debt),
+            30 * 10u64.pow(ACCURACCY.into())
+        );
+        // No collateral => whole debt is bad debt
+        let user_account_no_collateral = UserAccount {
+            collateral: 0,
+            shares: 1000,
+            ..Default::default()
+        };
+        assert_eq!(
+            calculate_bad_debt(&collateral_asset, &user_account_no_collateral, user_debt),
+            user_debt
+        );
+    }
+    #[test]
+    fn test_calculate_flash_mint_fee() {
+        let amount = 1000 * 10u64.pow(ACCURACCY.into());
+        // 0.09% fee
+        assert_eq!(
+            calculate_flash_mint_fee(amount, 9),
+            9 * 10u64.pow(ACCURACCY.into()) / 10
+        );
+        // Rounded up
+        assert_eq!(calculate_flash_mint_fee(1, 9), 1);
+        assert_eq!(calculate_flash_mint_fee(amount, 0), 0);
+    }
+    #[test]
+    fn test_calculate_interest() {
+        let debt = 1000 * 10u64.pow(ACCURACCY.into());
+        // 1% for whole year
+        let interest = calculate_interest(debt, 100, SECONDS_PER_YEAR as i64);
+        assert_eq!(interest, 10 * 10u64.pow(ACCURACCY.into()));
+        // 5% for half of year
+        let interest = calculate_interest(debt, 500, SECONDS_PER_YEAR as i64 / 2);
+        assert_eq!(interest, 25 * 10u64.pow(ACCURACCY.into()));
+        // No time passed
+        assert_eq!(calculate_interest(debt, 500, 0), 0);
+        // Zero rate
+        assert_eq!(calculate_interest(debt, 0, SECONDS_PER_YEAR as i64), 0);
+    }
 }
@@ -9,9 +9,14 @@ const {
   createAccountWithCollateral,
   createPriceFeed,
   mintUsd,
+  getUserPosition,
   updateAllFeeds,
   tou64,
-  newAccountWithLamports
+  newAccountWithLamports,
+  sleep,
+  getClockTimestamp,
+  waitForTimestamp,
+  USER_ACCOUNT_SIZE
 } = require('./utils')
 
 describe('system', () => {
@@ -32,6 +37,8 @@ describe('system', () => {
   let mintAuthority
   let collateralAccount
   let syntheticUsd
+  let treasury
+  let insuranceFund
   let nonce
   let collateralTokenFeed
   const initPrice = new anchor.BN(2 * 1e4)
@@ -51,6 +58,8 @@ describe('system', () => {
       collateralToken = await createToken({ connection, wallet, mintAuthority: wallet.publicKey })
       collateralAccount = await collateralToken.createAccount(mintAuthority)
       syntheticUsd = await createToken({ connection, wallet, mintAuthority })
+      treasury = await syntheticUsd.createAccount(mintAuthority)
+      insuranceFund = await syntheticUsd.createAccount(mintAuthority)
       await systemProgram.state.rpc.initialize(
         _nonce,
         signer.publicKey,
@@ -60,6 +69,9 @@ describe('system', () => {
         collateralTokenFeed.publicKey,
         syntheticUsd.publicKey,
         mintAuthority,
+        oracleProgram.programId,
+        treasury,
+        insuranceFund,
         {
           accounts: {}
         }
@@ -76,6 +88,8 @@ describe('system', () => {
     assert.ok(state.signer.equals(signer.publicKey))
     assert.ok(state.collateralToken.equals(collateralToken.publicKey))
     assert.ok(state.collateralAccount.equals(collateralAccount))
+    assert.ok(state.treasury.equals(treasury))
+    assert.ok(state.insuranceFund.equals(insuranceFund))
     assert.ok(state.debt.eq(new anchor.BN(0)))
     assert.ok(state.shares.eq(new anchor.BN(0)))
     // initaly we will have collateral and sythetic usd
@@ -95,8 +109,9 @@ describe('system', () => {
         rent: anchor.web3.SYSVAR_RENT_PUBKEY
       },
       signers: [userAccount],
-      // Auto allocate memory
-      instructions: [await systemProgram.account.userAccount.createInstruction(userAccount)]
+      instructions: [
+        await systemProgram.account.userAccount.createInstruction(userAccount, USER_ACCOUNT_SIZE)
+      ]
     })
     const account = await systemProgram.account.userAccount(userAccount.publicKey)
     assert.ok(account.shares.eq(new anchor.BN(0)))
@@ -134,16 +149,156 @@ describe('system', () => {
     assert.ok(accountAfterDeposit.collateral.eq(amount))
     assert.ok(accountAfterDeposit.owner.equals(userWallet.publicKey))
   })
-  it('#updatePrice()', async () => {
-    await systemProgram.state.rpc.updatePrice(collateralTokenFeed.publicKey, {
-      accounts: {
-        priceFeedAccount: collateralTokenFeed.publicKey,
-        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+  describe('#updatePrice()', () => {
+    it('updates registered feed', async () => {
+      await systemProgram.state.rpc.updatePrice({
+        accounts: {
+          priceFeedAccount: collateralTokenFeed.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
+      })
+      const state = await systemProgram.state()
+      const feed = await oracleProgram.account.priceFeed(collateralTokenFeed.publicKey)
+      // collateral will always have index 1
+      assert.ok(state.assets[1].price.eq(initPrice))
+      assert.ok(state.assets[1].lastUpdate.eq(feed.lastUpdate))
+    })
+    it('fails on unregistered feed', async () => {
+      const unknownFeed = await createPriceFeed({
+        admin,
+        oracleProgram,
+        initPrice: new anchor.BN(1000 * 1e4)
+      })
+      try {
+        await systemProgram.state.rpc.updatePrice({
+          accounts: {
+            priceFeedAccount: unknownFeed.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          }
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'No asset uses this price feed')
+      }
+      const state = await systemProgram.state()
+      assert.ok(state.assets[1].price.eq(initPrice))
+    })
+  })
+  describe('#updatePrices()', () => {
+    it('updates all registered feeds at once', async () => {
+      const newPrice = new anchor.BN(3 * 1e4)
+      const unknownFeed = await createPriceFeed({ admin, oracleProgram })
+      await oracleProgram.rpc.setPrice(newPrice, {
+        accounts: {
+          priceFeed: collateralTokenFeed.publicKey,
+          admin: admin.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        },
+        signers: [admin]
+      })
+      await systemProgram.state.rpc.updatePrices({
+        accounts: {
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        },
+        // unknown feed is skipped
+        remainingAccounts: [collateralTokenFeed.publicKey, unknownFeed.publicKey].map(
+          (pubkey) => ({ pubkey, isWritable: false, isSigner: false })
+        )
+      })
+      const state = await systemProgram.state()
+      assert.ok(state.assets[1].price.eq(newPrice))
+      // restore initial price
+      await oracleProgram.rpc.setPrice(initPrice, {
+        accounts: {
+          priceFeed: collateralTokenFeed.publicKey,
+          admin: admin.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        },
+        signers: [admin]
+      })
+      await systemProgram.state.rpc.updatePrice({
+        accounts: {
+          priceFeedAccount: collateralTokenFeed.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
+      })
+    })
+  })
+  describe('#setAssetMaxDelay()', () => {
+    it('sets max delay of asset', async () => {
+      const maxDelay = 3600
+      await systemProgram.state.rpc.setAssetMaxDelay(maxDelay, {
+        accounts: {
+          assetAddress: collateralToken.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const state = await systemProgram.state()
+      assert.ok(state.assets[1].maxDelay === maxDelay)
+      // fallback to global max delay
+      await systemProgram.state.rpc.setAssetMaxDelay(0, {
+        accounts: {
+          assetAddress: collateralToken.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const stateAfter = await systemProgram.state()
+      assert.ok(stateAfter.assets[1].maxDelay === 0)
+    })
+    it('fails without admin', async () => {
+      const notAdmin = new anchor.web3.Account()
+      try {
+        await systemProgram.state.rpc.setAssetMaxDelay(3600, {
+          accounts: {
+            assetAddress: collateralToken.publicKey,
+            admin: notAdmin.publicKey
+          },
+          signers: [notAdmin]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'You are not admin of system')
+      }
+    })
+  })
+  describe('#setAssetFee()', () => {
+    it('sets base fee of asset', async () => {
+      await systemProgram.state.rpc.setAssetFee(50, {
+        accounts: {
+          assetAddress: collateralToken.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const state = await systemProgram.state()
+      assert.ok(state.assets[1].fee === 50)
+      // fallback to global fee
+      await systemProgram.state.rpc.setAssetFee(0, {
+        accounts: {
+          assetAddress: collateralToken.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const stateAfter = await systemProgram.state()
+      assert.ok(stateAfter.assets[1].fee === 0)
+    })
+    it('fails on unknown asset', async () => {
+      try {
+        await systemProgram.state.rpc.setAssetFee(50, {
+          accounts: {
+            assetAddress: new anchor.web3.Account().publicKey,
+            admin: wallet.publicKey
+          },
+          signers: [wallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Asset not found')
       }
     })
-    const state = await systemProgram.state()
-    // collateral will always have index 1
-    assert.ok(state.assets[1].price.eq(initPrice))
   })
   describe('#mint()', () => {
     const firstMintAmount = new anchor.BN(1 * 1e8)
@@ -282,147 +437,205 @@ describe('system', () => {
     })
   })
 
-  describe('#widthdraw()', () => {
-    it('withdraw with zero debt', async () => {
-      const amountCollateral = new anchor.BN(100 * 1e8)
-      const {
-        userSystemAccount,
-        userCollateralTokenAccount,
-        userWallet
-      } = await createAccountWithCollateral({
+  describe('#getUserPosition()', () => {
+    it('returns position of user', async () => {
+      // 100 SNY at 2 usd each
+      const { userWallet, userSystemAccount } = await createAccountWithCollateral({
         collateralAccount,
         collateralToken,
         mintAuthority: wallet,
         systemProgram,
-        amount: amountCollateral
-      })
-      const stateBefore = await systemProgram.state()
-      await systemProgram.state.rpc.withdraw(amountCollateral, {
-        accounts: {
-          userAccount: userSystemAccount.publicKey,
-          authority: mintAuthority,
-          collateralAccount: collateralAccount,
-          to: userCollateralTokenAccount,
-          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
-          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
-          owner: userWallet.publicKey
-        },
-        signers: [userWallet],
-        instructions: await updateAllFeeds(stateBefore, systemProgram)
+        amount: new anchor.BN(100 * 1e8)
       })
-
-      const info = await collateralToken.getAccountInfo(userCollateralTokenAccount)
-      assert.ok(info.amount.eq(amountCollateral))
-
-      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
-      const stateAfter = await systemProgram.state()
-
-      assert.ok(account.collateral.eq(new anchor.BN(0)))
-      assert.ok(
-        stateBefore.collateralBalance.eq(stateAfter.collateralBalance.add(amountCollateral))
-      )
-    })
-    it('withdraw with debt', async () => {
-      const amountCollateral = new anchor.BN(100 * 1e8)
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
       const mintAmount = new anchor.BN(10 * 1e8)
-      const {
+      await mintUsd({
+        userWallet,
+        systemProgram,
         userSystemAccount,
-        userCollateralTokenAccount,
-        userWallet
-      } = await createAccountWithCollateral({
+        userTokenAccount,
+        mintAuthority,
+        mintAmount
+      })
+      const position = await getUserPosition({ systemProgram, userSystemAccount })
+      assert.ok(position.collateralValue.eq(new anchor.BN(200 * 1e8)))
+      // rounding of shares
+      assert.ok(position.debt.sub(mintAmount).abs().lte(new anchor.BN(1)))
+      assert.ok(position.poolShare.gt(new anchor.BN(0)))
+      assert.ok(position.collateralRatio.sub(new anchor.BN(2000)).abs().lte(new anchor.BN(1)))
+      // 40 usd can be minted in total
+      assert.ok(position.maxMintable.sub(new anchor.BN(30 * 1e8)).abs().lte(new anchor.BN(1)))
+      // 10 usd of debt locks 50 usd of collateral
+      assert.ok(position.maxWithdrawable.sub(new anchor.BN(75 * 1e8)).abs().lte(new anchor.BN(10)))
+      assert.ok(position.liquidationPrice.sub(new anchor.BN(0.5 * 1e4)).abs().lte(new anchor.BN(1)))
+    })
+  })
+  describe('#mint() caps', () => {
+    const mintAmount = new anchor.BN(1e8)
+    let userSystemAccount
+    let userWallet
+    let userTokenAccount
+    before(async () => {
+      const account = await createAccountWithCollateral({
         collateralAccount,
         collateralToken,
         mintAuthority: wallet,
         systemProgram,
-        amount: amountCollateral
+        amount: new anchor.BN(100 * 1e8)
       })
-      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
-      await mintUsd({
+      userSystemAccount = account.userSystemAccount
+      userWallet = account.userWallet
+      userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+    })
+    const mint = () =>
+      mintUsd({
         userWallet,
         systemProgram,
         userSystemAccount,
         userTokenAccount,
         mintAuthority,
-        mintAmount: mintAmount
+        mintAmount
       })
-      const stateBefore = await systemProgram.state()
-      // we should be able to withdraw 75 tokens
-      const amountCollateralWithdraw = new anchor.BN(75 * 1e8)
-
-      await systemProgram.state.rpc.withdraw(amountCollateralWithdraw, {
+    it('mint over debt ceiling', async () => {
+      await systemProgram.state.rpc.setDebtCeiling(new anchor.BN(1), {
+        accounts: { admin: wallet.publicKey },
+        signers: [wallet]
+      })
+      try {
+        await mint()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Debt ceiling crossed')
+      }
+      await systemProgram.state.rpc.setDebtCeiling(new anchor.BN(0), {
+        accounts: { admin: wallet.publicKey },
+        signers: [wallet]
+      })
+    })
+    it('mint over supply cap', async () => {
+      const state = await systemProgram.state()
+      await systemProgram.state.rpc.setAssetMaxSupply(state.assets[0].supply, {
+        accounts: {
+          assetAddress: syntheticUsd.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      try {
+        await mint()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Asset supply cap crossed')
+      }
+      await systemProgram.state.rpc.setAssetMaxSupply(new anchor.BN(0), {
+        accounts: {
+          assetAddress: syntheticUsd.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+    })
+    it('mint over user debt cap', async () => {
+      await systemProgram.state.rpc.setUserMaxDebt(mintAmount.div(new anchor.BN(2)), {
         accounts: {
           userAccount: userSystemAccount.publicKey,
-          authority: mintAuthority,
-          collateralAccount: collateralAccount,
-          to: userCollateralTokenAccount,
-          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
-          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
-          owner: userWallet.publicKey
+          admin: wallet.publicKey
         },
-        signers: [userWallet],
-        instructions: await updateAllFeeds(stateBefore, systemProgram)
+        signers: [wallet]
       })
-
-      const info = await collateralToken.getAccountInfo(userCollateralTokenAccount)
-      assert.ok(info.amount.eq(amountCollateralWithdraw))
-
-      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
-      const stateAfter = await systemProgram.state()
-
-      assert.ok(account.collateral.eq(amountCollateral.sub(amountCollateralWithdraw)))
-      assert.ok(
-        stateBefore.collateralBalance.eq(stateAfter.collateralBalance.add(amountCollateralWithdraw))
-      )
+      try {
+        await mint()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'User debt cap crossed')
+      }
+      await systemProgram.state.rpc.setUserMaxDebt(mintAmount, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      await mint()
+      const info = await syntheticUsd.getAccountInfo(userTokenAccount)
+      assert.ok(info.amount.eq(mintAmount))
     })
-    it('withdraw with debt over limit', async () => {
-      const amountCollateral = new anchor.BN(100 * 1e8)
-      const mintAmount = new anchor.BN(10 * 1e8)
-      const {
-        userSystemAccount,
-        userCollateralTokenAccount,
-        userWallet
-      } = await createAccountWithCollateral({
+  })
+  describe('#setPaused()', () => {
+    const PAUSE_MINT = 4
+    const PAUSE_SWAP = 16
+    it('paused mint fails', async () => {
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
         collateralAccount,
         collateralToken,
         mintAuthority: wallet,
         systemProgram,
-        amount: amountCollateral
+        amount: new anchor.BN(100 * 1e8)
       })
       const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await systemProgram.state.rpc.setPaused(PAUSE_MINT, {
+        accounts: { admin: wallet.publicKey },
+        signers: [wallet]
+      })
+      try {
+        await mintUsd({
+          userWallet,
+          systemProgram,
+          userSystemAccount,
+          userTokenAccount,
+          mintAuthority,
+          mintAmount: new anchor.BN(1e8)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Operation is paused')
+      }
+      await systemProgram.state.rpc.setPaused(0, {
+        accounts: { admin: wallet.publicKey },
+        signers: [wallet]
+      })
       await mintUsd({
         userWallet,
         systemProgram,
         userSystemAccount,
         userTokenAccount,
         mintAuthority,
-        mintAmount: mintAmount
+        mintAmount: new anchor.BN(1e8)
       })
-      const stateBefore = await systemProgram.state()
-      // we should be able to withdraw 75 tokens
-      const amountCollateralWithdraw = new anchor.BN(76 * 1e8)
+    })
+    it('guardian can only pause', async () => {
+      const guardian = new anchor.web3.Account()
+      await systemProgram.state.rpc.setGuardian(guardian.publicKey, {
+        accounts: { admin: wallet.publicKey },
+        signers: [wallet]
+      })
+      await systemProgram.state.rpc.setPaused(PAUSE_SWAP, {
+        accounts: { admin: guardian.publicKey },
+        signers: [guardian]
+      })
+      const state = await systemProgram.state()
+      assert.ok(state.paused === PAUSE_SWAP)
       try {
-        await systemProgram.state.rpc.withdraw(amountCollateralWithdraw, {
-          accounts: {
-            userAccount: userSystemAccount.publicKey,
-            authority: mintAuthority,
-            collateralAccount: collateralAccount,
-            to: userCollateralTokenAccount,
-            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
-            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
-            owner: userWallet.publicKey
-          },
-          signers: [userWallet],
-          instructions: await updateAllFeeds(stateBefore, systemProgram)
+        await systemProgram.state.rpc.setPaused(0, {
+          accounts: { admin: guardian.publicKey },
+          signers: [guardian]
         })
+        assert.ok(false)
       } catch (error) {
-        assert.ok(error.toString(), 'Not enough collateral')
+        assert.equal(error.toString(), 'You are not admin of system')
       }
+      await systemProgram.state.rpc.setPaused(0, {
+        accounts: { admin: wallet.publicKey },
+        signers: [wallet]
+      })
+      const stateAfter = await systemProgram.state()
+      assert.ok(stateAfter.paused === 0)
     })
   })
-  describe('#burn()', () => {
-    it('burn full', async () => {
-      const mintAmount = new u64(1e8)
-      const stateBefore = await systemProgram.state()
+  describe('#setDelegate()', () => {
+    const PERMISSION_SWAP = 1
+    const PERMISSION_MINT_BURN = 2
+    it('delegate mints with permission', async () => {
       const { userSystemAccount, userWallet } = await createAccountWithCollateral({
         collateralAccount,
         collateralToken,
@@ -430,60 +643,1467 @@ describe('system', () => {
         systemProgram,
         amount: new anchor.BN(100 * 1e8)
       })
+      const delegate = new anchor.web3.Account()
       const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
-      await mintUsd({
-        userWallet,
-        systemProgram,
-        userSystemAccount,
-        userTokenAccount,
-        mintAuthority,
-        mintAmount: mintAmount
+      await systemProgram.rpc.setDelegate(delegate.publicKey, PERMISSION_SWAP, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
       })
-
-      // await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], mintAmount)
-      // console.log(syntheticUsd.programId.toString())
-      // console.log(userTokenAccount.toString())
-      // console.log(mintAuthority.toString())
+      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      assert.ok(account.delegate.equals(delegate.publicKey))
+      assert.ok(account.delegatePermissions === PERMISSION_SWAP)
+      // Swap permission does not allow minting
+      try {
+        await mintUsd({
+          userWallet: delegate,
+          systemProgram,
+          userSystemAccount,
+          userTokenAccount,
+          mintAuthority,
+          mintAmount: new anchor.BN(1e8)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Signer is not owner or permitted delegate')
+      }
+      await systemProgram.rpc.setDelegate(
+        delegate.publicKey,
+        PERMISSION_SWAP | PERMISSION_MINT_BURN,
+        {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet]
+        }
+      )
+      await mintUsd({
+        userWallet: delegate,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: new anchor.BN(1e8)
+      })
+      const info = await syntheticUsd.getAccountInfo(userTokenAccount)
+      assert.ok(info.amount.eq(new anchor.BN(1e8)))
+    })
+    it('delegate can not mint to own account', async () => {
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const delegate = new anchor.web3.Account()
+      const delegateTokenAccount = await syntheticUsd.createAccount(delegate.publicKey)
+      await systemProgram.rpc.setDelegate(delegate.publicKey, PERMISSION_MINT_BURN, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
+      })
+      try {
+        await mintUsd({
+          userWallet: delegate,
+          systemProgram,
+          userSystemAccount,
+          userTokenAccount: delegateTokenAccount,
+          mintAuthority,
+          mintAmount: new anchor.BN(1e8)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Delegate can only send tokens to owner')
+      }
+      const info = await syntheticUsd.getAccountInfo(delegateTokenAccount)
+      assert.ok(info.amount.eq(new anchor.BN(0)))
+    })
+    it('revoked delegate can not mint', async () => {
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const delegate = new anchor.web3.Account()
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await systemProgram.rpc.setDelegate(delegate.publicKey, PERMISSION_MINT_BURN, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
+      })
+      await systemProgram.rpc.revokeDelegate({
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
+      })
+      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      assert.ok(account.delegate.equals(new anchor.web3.PublicKey(0)))
+      assert.ok(account.delegatePermissions === 0)
+      try {
+        await mintUsd({
+          userWallet: delegate,
+          systemProgram,
+          userSystemAccount,
+          userTokenAccount,
+          mintAuthority,
+          mintAmount: new anchor.BN(1e8)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Signer is not owner or permitted delegate')
+      }
+    })
+  })
+  describe('#widthdraw()', () => {
+    it('withdraw with zero debt', async () => {
+      const amountCollateral = new anchor.BN(100 * 1e8)
+      const {
+        userSystemAccount,
+        userCollateralTokenAccount,
+        userWallet
+      } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: amountCollateral
+      })
+      const stateBefore = await systemProgram.state()
+      await systemProgram.state.rpc.withdraw(amountCollateral, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          authority: mintAuthority,
+          collateralAccount: collateralAccount,
+          to: userCollateralTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet],
+        instructions: await updateAllFeeds(stateBefore, systemProgram)
+      })
+
+      const info = await collateralToken.getAccountInfo(userCollateralTokenAccount)
+      assert.ok(info.amount.eq(amountCollateral))
+
+      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      const stateAfter = await systemProgram.state()
+
+      assert.ok(account.collateral.eq(new anchor.BN(0)))
+      assert.ok(
+        stateBefore.collateralBalance.eq(stateAfter.collateralBalance.add(amountCollateral))
+      )
+    })
+    it('withdraw with debt', async () => {
+      const amountCollateral = new anchor.BN(100 * 1e8)
+      const mintAmount = new anchor.BN(10 * 1e8)
+      const {
+        userSystemAccount,
+        userCollateralTokenAccount,
+        userWallet
+      } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: amountCollateral
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      const stateBefore = await systemProgram.state()
+      // we should be able to withdraw 75 tokens
+      const amountCollateralWithdraw = new anchor.BN(75 * 1e8)
+
+      await systemProgram.state.rpc.withdraw(amountCollateralWithdraw, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          authority: mintAuthority,
+          collateralAccount: collateralAccount,
+          to: userCollateralTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet],
+        instructions: await updateAllFeeds(stateBefore, systemProgram)
+      })
+
+      const info = await collateralToken.getAccountInfo(userCollateralTokenAccount)
+      assert.ok(info.amount.eq(amountCollateralWithdraw))
+
+      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      const stateAfter = await systemProgram.state()
+
+      assert.ok(account.collateral.eq(amountCollateral.sub(amountCollateralWithdraw)))
+      assert.ok(
+        stateBefore.collateralBalance.eq(stateAfter.collateralBalance.add(amountCollateralWithdraw))
+      )
+    })
+    it('withdraw with debt over limit', async () => {
+      const amountCollateral = new anchor.BN(100 * 1e8)
+      const mintAmount = new anchor.BN(10 * 1e8)
+      const {
+        userSystemAccount,
+        userCollateralTokenAccount,
+        userWallet
+      } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: amountCollateral
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      const stateBefore = await systemProgram.state()
+      // we should be able to withdraw 75 tokens
+      const amountCollateralWithdraw = new anchor.BN(76 * 1e8)
+      try {
+        await systemProgram.state.rpc.withdraw(amountCollateralWithdraw, {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            collateralAccount: collateralAccount,
+            to: userCollateralTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(stateBefore, systemProgram)
+        })
+      } catch (error) {
+        assert.ok(error.toString(), 'Not enough collateral')
+      }
+    })
+    it('withdraw from treasury should fail', async () => {
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const state = await systemProgram.state()
+      try {
+        await systemProgram.state.rpc.withdraw(new anchor.BN(1), {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            collateralAccount: treasury,
+            to: userTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Wrong collateral account')
+      }
+    })
+  })
+  describe('#requestWithdrawal()', () => {
+    it('withdraws after unbonding period', async () => {
+      const amountCollateral = new anchor.BN(100 * 1e8)
+      const {
+        userSystemAccount,
+        userCollateralTokenAccount,
+        userWallet
+      } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: amountCollateral
+      })
+      const setUnbondingPeriod = async (period) => {
+        await systemProgram.state.rpc.setUnbondingPeriod(period, {
+          accounts: { admin: wallet.publicKey },
+          signers: [wallet]
+        })
+      }
+      const withdrawAccounts = {
+        userAccount: userSystemAccount.publicKey,
+        authority: mintAuthority,
+        collateralAccount: collateralAccount,
+        to: userCollateralTokenAccount,
+        tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        owner: userWallet.publicKey
+      }
+      await setUnbondingPeriod(2)
+      const state = await systemProgram.state()
+      try {
+        await systemProgram.state.rpc.withdraw(amountCollateral, {
+          accounts: withdrawAccounts,
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Withdrawal requires unbonding')
+      }
+      await systemProgram.state.rpc.requestWithdrawal(amountCollateral, {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet],
+        instructions: await updateAllFeeds(state, systemProgram)
+      })
+      const accountRequested = await systemProgram.account.userAccount(
+        userSystemAccount.publicKey
+      )
+      // unbonding collateral no longer backs debt
+      assert.ok(accountRequested.collateral.eq(new anchor.BN(0)))
+      assert.ok(accountRequested.unbondingAmount.eq(amountCollateral))
+      try {
+        await systemProgram.state.rpc.claimWithdrawal({
+          accounts: withdrawAccounts,
+          signers: [userWallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Unbonding period not finished')
+      }
+      await sleep(3000)
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      try {
+        await systemProgram.state.rpc.claimWithdrawal({
+          accounts: { ...withdrawAccounts, collateralAccount: treasury, to: userTokenAccount },
+          signers: [userWallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Wrong collateral account')
+      }
+      await systemProgram.state.rpc.claimWithdrawal({
+        accounts: withdrawAccounts,
+        signers: [userWallet]
+      })
+      await setUnbondingPeriod(0)
+      const info = await collateralToken.getAccountInfo(userCollateralTokenAccount)
+      assert.ok(info.amount.eq(amountCollateral))
+      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      assert.ok(account.unbondingAmount.eq(new anchor.BN(0)))
+    })
+  })
+  describe('#createVesting()', () => {
+    it('escrows collateral until vested', async () => {
+      const { userWallet, userSystemAccount } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const rewardAmount = new anchor.BN(50 * 1e8)
+      const rewardTokenAccount = await collateralToken.createAccount(wallet.publicKey)
+      await collateralToken.mintTo(rewardTokenAccount, wallet, [], tou64(rewardAmount))
+      const start = Math.floor(Date.now() / 1000)
+      await systemProgram.state.rpc.createVesting(
+        new anchor.BN(start),
+        new anchor.BN(start + 2),
+        {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            collateralAccount: collateralAccount,
+            admin: wallet.publicKey
+          },
+          signers: [wallet],
+          instructions: [
+            Token.createTransferInstruction(
+              collateralToken.programId,
+              rewardTokenAccount,
+              collateralAccount,
+              wallet.publicKey,
+              [],
+              tou64(rewardAmount)
+            )
+          ]
+        }
+      )
+      const accountEscrowed = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      // escrow is not withdrawable collateral
+      assert.ok(accountEscrowed.collateral.eq(new anchor.BN(100 * 1e8)))
+      assert.ok(accountEscrowed.vesting.length === 1)
+      assert.ok(accountEscrowed.vesting[0].amount.eq(rewardAmount))
+      await sleep(3000)
+      await systemProgram.rpc.claimVested({
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
+      })
+      const account = await systemProgram.account.userAccount(userSystemAccount.publicKey)
+      assert.ok(account.collateral.eq(new anchor.BN(100 * 1e8).add(rewardAmount)))
+      assert.ok(account.vesting.length === 0)
+    })
+  })
+  describe('#burn()', () => {
+    it('burn full', async () => {
+      const mintAmount = new u64(1e8)
+      const stateBefore = await systemProgram.state()
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+
+      // await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], mintAmount)
+      // console.log(syntheticUsd.programId.toString())
+      // console.log(userTokenAccount.toString())
+      // console.log(mintAuthority.toString())
       // console.log(userWallet.publicKey.toString())
       const approveTx = Token.createApproveInstruction(
         syntheticUsd.programId,
         userTokenAccount,
         mintAuthority,
-        userWallet.publicKey,
+        userWallet.publicKey,
+        [],
+        tou64(mintAmount)
+      )
+      // console.log(approveTx)
+      const oracleTxs = await updateAllFeeds(stateBefore, systemProgram)
+      oracleTxs.push(approveTx)
+      await systemProgram.state.rpc.burn(mintAmount, new anchor.BN(0), {
+        accounts: {
+          authority: mintAuthority,
+          mint: syntheticUsd.publicKey,
+          userAccount: userSystemAccount.publicKey,
+          userTokenAccount: userTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet],
+        instructions: oracleTxs
+      })
+      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
+      const stateAfter = await systemProgram.state()
+      const systemAccountAfter = await systemProgram.account.userAccount(
+        userSystemAccount.publicKey
+      )
+      assert.ok(stateAfter.shares.eq(stateBefore.shares))
+      assert.ok(stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply))
+      assert.ok(systemAccountAfter.shares.eq(new u64(0)))
+      assert.ok(accountAfter.amount.eq(new u64(0)))
+    })
+    it('burn partial', async () => {
+      const mintAmount = new u64(1e8)
+      const burnAmount = mintAmount.div(new u64(5))
+      const initialShares = new u64(1e8)
+      const stateBefore = await systemProgram.state()
+
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], tou64(burnAmount))
+      await systemProgram.state.rpc.burn(burnAmount, new anchor.BN(0), {
+        accounts: {
+          authority: mintAuthority,
+          mint: syntheticUsd.publicKey,
+          userAccount: userSystemAccount.publicKey,
+          userTokenAccount: userTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
+      })
+      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
+      const stateAfter = await systemProgram.state()
+      const systemAccountAfter = await systemProgram.account.userAccount(
+        userSystemAccount.publicKey
+      )
+      assert.ok(
+        stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply.add(mintAmount.sub(burnAmount)))
+      )
+      assert.ok(accountAfter.amount.eq(mintAmount.sub(burnAmount)))
+
+      // we burn 1/5 of shares
+      assert.ok(
+        systemAccountAfter.shares.eq(initialShares.sub(initialShares.div(new anchor.BN(5))))
+      )
+      assert.ok(
+        stateAfter.shares.eq(
+          stateBefore.shares.add(initialShares.sub(initialShares.div(new anchor.BN(5))))
+        )
+      )
+    })
+    it('burn over limit', async () => {
+      const stateBefore = await systemProgram.state()
+
+      const mintAmount = new u64(1e8)
+      const burnAmount = mintAmount.mul(new anchor.BN(2))
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+
+      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], tou64(burnAmount))
+      await systemProgram.state.rpc.burn(burnAmount, new anchor.BN(0), {
+        accounts: {
+          authority: mintAuthority,
+          mint: syntheticUsd.publicKey,
+          userAccount: userSystemAccount.publicKey,
+          userTokenAccount: userTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet]
+      })
+      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
+      const stateAfter = await systemProgram.state()
+      const systemAccountAfter = await systemProgram.account.userAccount(
+        userSystemAccount.publicKey
+      )
+      assert.ok(stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply))
+      assert.ok(accountAfter.amount.eq(new anchor.BN(0)))
+
+      assert.ok(systemAccountAfter.shares.eq(new anchor.BN(0)))
+      assert.ok(stateAfter.shares.eq(stateBefore.shares))
+    })
+    it('burn over balance', async () => {
+      const mintAmount = new u64(2 * 1e8)
+      const burnAmount = new u64(1e8)
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const otherTokenAccount = await syntheticUsd.createAccount(wallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      // Leave only half of burned amount
+      await syntheticUsd.transfer(
+        userTokenAccount,
+        otherTokenAccount,
+        userWallet,
+        [],
+        tou64(mintAmount.sub(burnAmount.div(new anchor.BN(2))))
+      )
+      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], tou64(burnAmount))
+      try {
+        await systemProgram.state.rpc.burn(burnAmount, new anchor.BN(0), {
+          accounts: {
+            authority: mintAuthority,
+            mint: syntheticUsd.publicKey,
+            userAccount: userSystemAccount.publicKey,
+            userTokenAccount: userTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Not enough tokens to burn')
+      }
+      const systemAccountAfter = await systemProgram.account.userAccount(
+        userSystemAccount.publicKey
+      )
+      assert.ok(systemAccountAfter.shares.gt(new anchor.BN(0)))
+    })
+    it('burn below min debt repaid', async () => {
+      const mintAmount = new u64(1e8)
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], tou64(mintAmount))
+      try {
+        await systemProgram.state.rpc.burn(mintAmount, mintAmount.add(new anchor.BN(1)), {
+          accounts: {
+            authority: mintAuthority,
+            mint: syntheticUsd.publicKey,
+            userAccount: userSystemAccount.publicKey,
+            userTokenAccount: userTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Slippage limit exceeded')
+      }
+      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
+      assert.ok(accountAfter.amount.eq(mintAmount))
+    })
+    it('burn from token account of other owner', async () => {
+      const mintAmount = new u64(1e8)
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const otherTokenAccount = await syntheticUsd.createAccount(wallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      await syntheticUsd.transfer(userTokenAccount, otherTokenAccount, userWallet, [], mintAmount)
+      await syntheticUsd.approve(otherTokenAccount, mintAuthority, wallet, [], tou64(mintAmount))
+      try {
+        await systemProgram.state.rpc.burn(mintAmount, new anchor.BN(0), {
+          accounts: {
+            authority: mintAuthority,
+            mint: syntheticUsd.publicKey,
+            userAccount: userSystemAccount.publicKey,
+            userTokenAccount: otherTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Token account is not owned by signer')
+      }
+      const accountAfter = await syntheticUsd.getAccountInfo(otherTokenAccount)
+      assert.ok(accountAfter.amount.eq(mintAmount))
+    })
+  })
+  describe('#repayFor()', () => {
+    it('repays debt of other account', async () => {
+      const mintAmount = new u64(1e8)
+      const repayAmount = mintAmount.div(new u64(2))
+      const user = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const payer = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(user.userWallet.publicKey)
+      const payerTokenAccount = await syntheticUsd.createAccount(payer.userWallet.publicKey)
+      await mintUsd({
+        userWallet: user.userWallet,
+        systemProgram,
+        userSystemAccount: user.userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      await mintUsd({
+        userWallet: payer.userWallet,
+        systemProgram,
+        userSystemAccount: payer.userSystemAccount,
+        userTokenAccount: payerTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      const userAccountBefore = await systemProgram.account.userAccount(
+        user.userSystemAccount.publicKey
+      )
+      const payerAccountBefore = await systemProgram.account.userAccount(
+        payer.userSystemAccount.publicKey
+      )
+      await syntheticUsd.approve(
+        payerTokenAccount,
+        mintAuthority,
+        payer.userWallet,
+        [],
+        tou64(repayAmount)
+      )
+      await systemProgram.state.rpc.repayFor(repayAmount, new anchor.BN(0), {
+        accounts: {
+          authority: mintAuthority,
+          mint: syntheticUsd.publicKey,
+          payerTokenAccount: payerTokenAccount,
+          userAccount: user.userSystemAccount.publicKey,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          payer: payer.userWallet.publicKey
+        },
+        signers: [payer.userWallet]
+      })
+      const userAccountAfter = await systemProgram.account.userAccount(
+        user.userSystemAccount.publicKey
+      )
+      const payerAccountAfter = await systemProgram.account.userAccount(
+        payer.userSystemAccount.publicKey
+      )
+      const payerTokenAccountInfo = await syntheticUsd.getAccountInfo(payerTokenAccount)
+      const userTokenAccountInfo = await syntheticUsd.getAccountInfo(userTokenAccount)
+      // debt is repaid only for user
+      assert.ok(userAccountAfter.shares.lt(userAccountBefore.shares))
+      assert.ok(payerAccountAfter.shares.eq(payerAccountBefore.shares))
+      assert.ok(payerTokenAccountInfo.amount.eq(mintAmount.sub(repayAmount)))
+      assert.ok(userTokenAccountInfo.amount.eq(mintAmount))
+    })
+  })
+  describe('#flashMint()', () => {
+    const flashReceiver = anchor.workspace.FlashReceiver
+    const repayIx = (receiver, owner, amount) =>
+      flashReceiver.instruction.repay(amount, {
+        accounts: {
+          receiver,
+          authority: mintAuthority,
+          owner,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID
+        }
+      })
+    it('mints and burns back with fee in one call', async () => {
+      const { userWallet, userSystemAccount } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const mintAmount = new u64(1e8)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount
+      })
+      const flashAmount = new u64(10 * 1e8)
+      // 0.09% rounded up
+      const fee = flashAmount.muln(9).addn(9999).divn(10000)
+      const ix = repayIx(userTokenAccount, userWallet.publicKey, flashAmount.add(fee))
+      const state = await systemProgram.state()
+      await systemProgram.state.rpc.flashMint(flashAmount, ix.data, {
+        accounts: {
+          authority: mintAuthority,
+          mint: syntheticUsd.publicKey,
+          receiver: userTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          receiverProgram: flashReceiver.programId,
+          initiator: userWallet.publicKey
+        },
+        remainingAccounts: ix.keys,
+        signers: [userWallet]
+      })
+      const stateAfter = await systemProgram.state()
+      const userTokenAccountInfo = await syntheticUsd.getAccountInfo(userTokenAccount)
+      assert.ok(userTokenAccountInfo.amount.eq(mintAmount.sub(fee)))
+      assert.ok(stateAfter.assets[0].supply.eq(state.assets[0].supply.sub(fee)))
+    })
+    it('fails if fee is not returned', async () => {
+      const userWallet = await newAccountWithLamports(connection)
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const flashAmount = new u64(1e8)
+      // Receiver only holds borrowed amount
+      const ix = repayIx(userTokenAccount, userWallet.publicKey, flashAmount)
+      try {
+        await systemProgram.state.rpc.flashMint(flashAmount, ix.data, {
+          accounts: {
+            authority: mintAuthority,
+            mint: syntheticUsd.publicKey,
+            receiver: userTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            receiverProgram: flashReceiver.programId,
+            initiator: userWallet.publicKey
+          },
+          remainingAccounts: ix.keys,
+          signers: [userWallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        // Burn fails in token program
+        assert.ok(!(error instanceof assert.AssertionError))
+      }
+      const userTokenAccountInfo = await syntheticUsd.getAccountInfo(userTokenAccount)
+      assert.ok(userTokenAccountInfo.amount.eq(new u64(0)))
+    })
+    it('fails with token account of other owner', async () => {
+      const { userWallet, userSystemAccount } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const mintAmount = new u64(1e8)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount
+      })
+      // Leftover approval of victim must not pay fee of someone else's flash mint
+      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], mintAmount)
+      const attacker = await newAccountWithLamports(connection)
+      const flashAmount = new u64(10 * 1e8)
+      const ix = repayIx(userTokenAccount, userWallet.publicKey, new u64(0))
+      try {
+        await systemProgram.state.rpc.flashMint(flashAmount, ix.data, {
+          accounts: {
+            authority: mintAuthority,
+            mint: syntheticUsd.publicKey,
+            receiver: userTokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            receiverProgram: flashReceiver.programId,
+            initiator: attacker.publicKey
+          },
+          remainingAccounts: ix.keys.map((x) => ({ ...x, isSigner: false })),
+          signers: [attacker]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Token account is not owned by signer')
+      }
+      const userTokenAccountInfo = await syntheticUsd.getAccountInfo(userTokenAccount)
+      assert.ok(userTokenAccountInfo.amount.eq(mintAmount))
+    })
+  })
+  describe('#swap(', () => {
+    it('swaps synthetic usd to other token', async () => {
+      const tokenPrice = new anchor.BN(2 * 1e4)
+      const mintedSyntheticUsd = new anchor.BN(100 * 1e8)
+      const newToken = await createToken({ connection, mintAuthority, wallet })
+      const tokenFeed = await createPriceFeed({ admin, oracleProgram, tokenPrice })
+
+      // TODO: Create and Add price feed to this new token
+      await systemProgram.state.rpc.addAsset(Buffer.from('test'), {
+        accounts: {
+          assetAddress: newToken.publicKey,
+          feedAddress: tokenFeed.publicKey,
+          admin: wallet.publicKey
+        },
+        signer: [wallet]
+      })
+      const state = await systemProgram.state()
+      assert.ok(state.assets.length === 3)
+      assert.ok(state.assets[2].feedAddress.equals(tokenFeed.publicKey))
+      assert.ok(state.assets[2].assetAddress.equals(newToken.publicKey))
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(10000 * 1e8)
+      })
+      const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const userNewTokenAccount = await newToken.createAccount(userWallet.publicKey)
+      const stateUpdated = await systemProgram.state()
+
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount: userSyntheticUsdAccount,
+        mintAuthority,
+        mintAmount: mintedSyntheticUsd
+      })
+      await syntheticUsd.approve(
+        userSyntheticUsdAccount,
+        mintAuthority,
+        userWallet,
+        [],
+        tou64(mintedSyntheticUsd)
+      )
+
+      const treasuryBefore = await syntheticUsd.getAccountInfo(treasury)
+      await systemProgram.state.rpc.swap(mintedSyntheticUsd, new anchor.BN('4985000000'), {
+        accounts: {
+          userAccount: userSystemAccount.publicKey,
+          authority: mintAuthority,
+          tokenIn: syntheticUsd.publicKey,
+          tokenFor: newToken.publicKey,
+          userTokenAccountIn: userSyntheticUsdAccount,
+          userTokenAccountFor: userNewTokenAccount,
+          usdToken: syntheticUsd.publicKey,
+          treasury: treasury,
+          insuranceFund: insuranceFund,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          owner: userWallet.publicKey
+        },
+        signers: [userWallet],
+        instructions: await updateAllFeeds(stateUpdated, systemProgram)
+      })
+      const accountUsd = await syntheticUsd.getAccountInfo(userSyntheticUsdAccount)
+      const accountNewToken = await newToken.getAccountInfo(userNewTokenAccount)
+      assert.ok(accountUsd.amount.eq(new anchor.BN(0)))
+      assert.ok(accountNewToken.amount.eq(new anchor.BN('4985000000')))
+      // 20% of 0.3 usd fee goes to treasury
+      const treasuryAfter = await syntheticUsd.getAccountInfo(treasury)
+      assert.ok(treasuryAfter.amount.eq(treasuryBefore.amount.add(new anchor.BN(6 * 1e6))))
+    })
+    it('fails below min amount out', async () => {
+      const mintedSyntheticUsd = new anchor.BN(10 * 1e8)
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(1000 * 1e8)
+      })
+      const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount: userSyntheticUsdAccount,
+        mintAuthority,
+        mintAmount: mintedSyntheticUsd
+      })
+      await syntheticUsd.approve(
+        userSyntheticUsdAccount,
+        mintAuthority,
+        userWallet,
+        [],
+        tou64(mintedSyntheticUsd)
+      )
+      const state = await systemProgram.state()
+      try {
+        // Fee makes it impossible to get back the same amount
+        await systemProgram.state.rpc.swap(mintedSyntheticUsd, mintedSyntheticUsd, {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            tokenIn: syntheticUsd.publicKey,
+            tokenFor: syntheticUsd.publicKey,
+            userTokenAccountIn: userSyntheticUsdAccount,
+            userTokenAccountFor: userSyntheticUsdAccount,
+            usdToken: syntheticUsd.publicKey,
+            treasury: treasury,
+            insuranceFund: insuranceFund,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Slippage limit exceeded')
+      }
+    })
+    it('swap from token account of other owner', async () => {
+      const mintAmount = new u64(1e8)
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const otherTokenAccount = await syntheticUsd.createAccount(wallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: mintAmount
+      })
+      await syntheticUsd.transfer(userTokenAccount, otherTokenAccount, userWallet, [], mintAmount)
+      await syntheticUsd.approve(otherTokenAccount, mintAuthority, wallet, [], tou64(mintAmount))
+      const state = await systemProgram.state()
+      try {
+        await systemProgram.state.rpc.swap(mintAmount, new anchor.BN(0), {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            tokenIn: syntheticUsd.publicKey,
+            tokenFor: syntheticUsd.publicKey,
+            userTokenAccountIn: otherTokenAccount,
+            userTokenAccountFor: userTokenAccount,
+            usdToken: syntheticUsd.publicKey,
+            treasury: treasury,
+            insuranceFund: insuranceFund,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Token account is not owned by signer')
+      }
+      const accountAfter = await syntheticUsd.getAccountInfo(otherTokenAccount)
+      assert.ok(accountAfter.amount.eq(mintAmount))
+    })
+  })
+  describe('#swapExactOut()', () => {
+    const swapAccounts = ({ userSystemAccount, userWallet, userSyntheticUsdAccount }) => ({
+      userAccount: userSystemAccount.publicKey,
+      authority: mintAuthority,
+      tokenIn: syntheticUsd.publicKey,
+      tokenFor: syntheticUsd.publicKey,
+      userTokenAccountIn: userSyntheticUsdAccount,
+      userTokenAccountFor: userSyntheticUsdAccount,
+      usdToken: syntheticUsd.publicKey,
+      treasury: treasury,
+      insuranceFund: insuranceFund,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      owner: userWallet.publicKey
+    })
+    const prepareUser = async (mintedSyntheticUsd) => {
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(1000 * 1e8)
+      })
+      const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount: userSyntheticUsdAccount,
+        mintAuthority,
+        mintAmount: mintedSyntheticUsd
+      })
+      await syntheticUsd.approve(
+        userSyntheticUsdAccount,
+        mintAuthority,
+        userWallet,
         [],
-        tou64(mintAmount)
+        tou64(mintedSyntheticUsd)
       )
-      // console.log(approveTx)
-      const oracleTxs = await updateAllFeeds(stateBefore, systemProgram)
-      oracleTxs.push(approveTx)
-      await systemProgram.state.rpc.burn(mintAmount, {
+      return { userSystemAccount, userWallet, userSyntheticUsdAccount }
+    }
+    it('swaps for exact amount', async () => {
+      const mintedSyntheticUsd = new anchor.BN(10 * 1e8)
+      const amountOut = new anchor.BN(997 * 1e6)
+      const user = await prepareUser(mintedSyntheticUsd)
+      const state = await systemProgram.state()
+      // 0.3% fee means whole 10 usd has to be paid
+      await systemProgram.state.rpc.swapExactOut(amountOut, mintedSyntheticUsd, {
+        accounts: swapAccounts(user),
+        signers: [user.userWallet],
+        instructions: await updateAllFeeds(state, systemProgram)
+      })
+      const accountUsd = await syntheticUsd.getAccountInfo(user.userSyntheticUsdAccount)
+      assert.ok(accountUsd.amount.eq(amountOut))
+    })
+    it('fails over max amount in', async () => {
+      const mintedSyntheticUsd = new anchor.BN(10 * 1e8)
+      const user = await prepareUser(mintedSyntheticUsd)
+      const state = await systemProgram.state()
+      try {
+        await systemProgram.state.rpc.swapExactOut(mintedSyntheticUsd, mintedSyntheticUsd, {
+          accounts: swapAccounts(user),
+          signers: [user.userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Slippage limit exceeded')
+      }
+    })
+  })
+  describe('#swapRoute()', () => {
+    it('swaps through intermediate asset', async () => {
+      const mintedSyntheticUsd = new anchor.BN(10 * 1e8)
+      const { userSystemAccount, userWallet } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(1000 * 1e8)
+      })
+      const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount: userSyntheticUsdAccount,
+        mintAuthority,
+        mintAmount: mintedSyntheticUsd
+      })
+      await syntheticUsd.approve(
+        userSyntheticUsdAccount,
+        mintAuthority,
+        userWallet,
+        [],
+        tou64(mintedSyntheticUsd)
+      )
+      const state = await systemProgram.state()
+      // asset added in swap tests
+      const intermediateAsset = state.assets[2]
+      await systemProgram.state.rpc.swapRoute(mintedSyntheticUsd, new anchor.BN(0), {
         accounts: {
-          authority: mintAuthority,
-          mint: syntheticUsd.publicKey,
           userAccount: userSystemAccount.publicKey,
-          userTokenAccount: userTokenAccount,
+          authority: mintAuthority,
+          tokenIn: syntheticUsd.publicKey,
+          tokenFor: syntheticUsd.publicKey,
+          userTokenAccountIn: userSyntheticUsdAccount,
+          userTokenAccountFor: userSyntheticUsdAccount,
+          usdToken: syntheticUsd.publicKey,
+          treasury: treasury,
+          insuranceFund: insuranceFund,
           tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
           clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
           owner: userWallet.publicKey
         },
+        remainingAccounts: [
+          { pubkey: intermediateAsset.assetAddress, isWritable: false, isSigner: false }
+        ],
         signers: [userWallet],
-        instructions: oracleTxs
+        instructions: await updateAllFeeds(state, systemProgram)
       })
-      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
       const stateAfter = await systemProgram.state()
-      const systemAccountAfter = await systemProgram.account.userAccount(
+      const accountUsd = await syntheticUsd.getAccountInfo(userSyntheticUsdAccount)
+      // fee is charged once
+      assert.ok(accountUsd.amount.eq(new anchor.BN(997 * 1e6)))
+      assert.ok(stateAfter.assets[2].supply.eq(intermediateAsset.supply))
+    })
+  })
+  describe('#coverBadDebt()', () => {
+    it('covers shortfall from insurance fund', async () => {
+      const { userWallet, userSystemAccount } = await createAccountWithCollateral({
+        collateralAccount,
+        collateralToken,
+        mintAuthority: wallet,
+        systemProgram,
+        amount: new anchor.BN(100 * 1e8)
+      })
+      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      await mintUsd({
+        userWallet,
+        systemProgram,
+        userSystemAccount,
+        userTokenAccount,
+        mintAuthority,
+        mintAmount: new u64(30 * 1e8)
+      })
+      const coverBadDebt = async () => {
+        const state = await systemProgram.state()
+        await systemProgram.state.rpc.coverBadDebt({
+          accounts: {
+            authority: mintAuthority,
+            mint: syntheticUsd.publicKey,
+            insuranceFund: insuranceFund,
+            userAccount: userSystemAccount.publicKey,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          },
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+      }
+      try {
+        await coverBadDebt()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'User has no bad debt')
+      }
+      // collateral worth 20 usd against 30 usd of debt
+      const setCollateralPrice = async (price) => {
+        await oracleProgram.rpc.setPrice(price, {
+          accounts: {
+            priceFeed: collateralTokenFeed.publicKey,
+            admin: admin.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          },
+          signers: [admin]
+        })
+      }
+      await setCollateralPrice(new anchor.BN(0.2 * 1e4))
+      // swaps funded insurance fund
+      const insuranceFundBefore = await syntheticUsd.getAccountInfo(insuranceFund)
+      assert.ok(insuranceFundBefore.amount.gt(new u64(0)))
+      const userAccountBefore = await systemProgram.account.userAccount(
         userSystemAccount.publicKey
       )
-      assert.ok(stateAfter.shares.eq(stateBefore.shares))
-      assert.ok(stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply))
-      assert.ok(systemAccountAfter.shares.eq(new u64(0)))
-      assert.ok(accountAfter.amount.eq(new u64(0)))
+      const stateBefore = await systemProgram.state()
+      await coverBadDebt()
+      const userAccountAfter = await systemProgram.account.userAccount(
+        userSystemAccount.publicKey
+      )
+      const stateAfter = await systemProgram.state()
+      const insuranceFundAfter = await syntheticUsd.getAccountInfo(insuranceFund)
+      // fund is smaller than shortfall so all of it is burned
+      assert.ok(insuranceFundAfter.amount.eq(new u64(0)))
+      assert.ok(
+        stateAfter.assets[0].supply.eq(
+          stateBefore.assets[0].supply.sub(insuranceFundBefore.amount)
+        )
+      )
+      // position is closed
+      assert.ok(userAccountAfter.shares.eq(new anchor.BN(0)))
+      assert.ok(userAccountAfter.collateral.eq(new anchor.BN(0)))
+      assert.ok(stateAfter.shares.eq(stateBefore.shares.sub(userAccountBefore.shares)))
+      // seized collateral stays in collateral account
+      assert.ok(stateAfter.collateralBalance.eq(stateBefore.collateralBalance))
+      // restore initial price
+      await setCollateralPrice(initPrice)
+      await systemProgram.state.rpc.updatePrice({
+        accounts: {
+          priceFeedAccount: collateralTokenFeed.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
+      })
     })
-    it('burn partial', async () => {
-      const mintAmount = new u64(1e8)
-      const burnAmount = mintAmount.div(new u64(5))
-      const initialShares = new u64(1e8)
+  })
+  describe('#accrueInterest()', () => {
+    const accrueInterestAccounts = () => ({
+      authority: mintAuthority,
+      mint: syntheticUsd.publicKey,
+      treasury: treasury,
+      insuranceFund: insuranceFund,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+    })
+    it('mints interest to treasury', async () => {
+      await systemProgram.state.rpc.setDebtInterestRate(1000, {
+        accounts: {
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      await sleep(2000)
       const stateBefore = await systemProgram.state()
+      const treasuryBefore = await syntheticUsd.getAccountInfo(treasury)
+      await systemProgram.state.rpc.accrueInterest({
+        accounts: accrueInterestAccounts(),
+        instructions: await updateAllFeeds(stateBefore, systemProgram)
+      })
+      const stateAfter = await systemProgram.state()
+      const treasuryAfter = await syntheticUsd.getAccountInfo(treasury)
+      const accrued = treasuryAfter.amount.sub(treasuryBefore.amount)
+      assert.ok(accrued.gt(new anchor.BN(0)))
+      assert.ok(stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply.add(accrued)))
+      assert.ok(stateAfter.shares.eq(stateBefore.shares))
+    })
+    it('fails to change rate without accrual', async () => {
+      await sleep(2000)
+      try {
+        await systemProgram.state.rpc.setDebtInterestRate(0, {
+          accounts: {
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            admin: wallet.publicKey
+          },
+          signers: [wallet]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Interest has to be accrued first')
+      }
+    })
+    it('changes rate after accrual', async () => {
+      const state = await systemProgram.state()
+      const oracleTxs = await updateAllFeeds(state, systemProgram)
+      oracleTxs.push(
+        await systemProgram.state.instruction.accrueInterest({
+          accounts: accrueInterestAccounts()
+        })
+      )
+      await systemProgram.state.rpc.setDebtInterestRate(0, {
+        accounts: {
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          admin: wallet.publicKey
+        },
+        signers: [wallet],
+        instructions: oracleTxs
+      })
+      const stateAfter = await systemProgram.state()
+      assert.ok(stateAfter.debtInterestRate === 0)
+    })
+  })
+  describe('#withdrawTreasury()', () => {
+    it('withdraws treasury funds', async () => {
+      const amount = new anchor.BN(1e6)
+      const adminTokenAccount = await syntheticUsd.createAccount(wallet.publicKey)
+      const treasuryBefore = await syntheticUsd.getAccountInfo(treasury)
+      await systemProgram.state.rpc.withdrawTreasury(amount, {
+        accounts: {
+          authority: mintAuthority,
+          treasury: treasury,
+          to: adminTokenAccount,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const treasuryAfter = await syntheticUsd.getAccountInfo(treasury)
+      const adminAccountInfo = await syntheticUsd.getAccountInfo(adminTokenAccount)
+      assert.ok(treasuryAfter.amount.eq(treasuryBefore.amount.sub(amount)))
+      assert.ok(adminAccountInfo.amount.eq(amount))
+    })
+    it('fails without admin', async () => {
+      const notAdmin = new anchor.web3.Account()
+      const tokenAccount = await syntheticUsd.createAccount(notAdmin.publicKey)
+      try {
+        await systemProgram.state.rpc.withdrawTreasury(new anchor.BN(1), {
+          accounts: {
+            authority: mintAuthority,
+            treasury: treasury,
+            to: tokenAccount,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            admin: notAdmin.publicKey
+          },
+          signers: [notAdmin]
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'You are not admin of system')
+      }
+    })
+  })
+  describe('#addInverseAsset()', () => {
+    it('derives price from feed and freezes at limit', async () => {
+      const entryPrice = new anchor.BN(2 * 1e4)
+      const inverseToken = await createToken({ connection, mintAuthority, wallet })
+      const underlyingFeed = await createPriceFeed({ admin, oracleProgram, initPrice: entryPrice })
+      await systemProgram.state.rpc.addInverseAsset(
+        Buffer.from('iTST'),
+        entryPrice,
+        new anchor.BN(1 * 1e4),
+        new anchor.BN(3 * 1e4),
+        {
+          accounts: {
+            assetAddress: inverseToken.publicKey,
+            feedAddress: underlyingFeed.publicKey,
+            admin: wallet.publicKey
+          },
+          signers: [wallet]
+        }
+      )
+      const setUnderlyingPrice = async (price) => {
+        await oracleProgram.rpc.setPrice(price, {
+          accounts: {
+            priceFeed: underlyingFeed.publicKey,
+            admin: admin.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          },
+          signers: [admin]
+        })
+        await systemProgram.state.rpc.updatePrice({
+          accounts: {
+            priceFeedAccount: underlyingFeed.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          }
+        })
+        const state = await systemProgram.state()
+        return state.assets.find((asset) => asset.assetAddress.equals(inverseToken.publicKey))
+      }
+      const inverse = await setUnderlyingPrice(new anchor.BN(2.5 * 1e4))
+      assert.ok(inverse.price.eq(new anchor.BN(1.5 * 1e4)))
+      assert.ok(inverse.frozen === false)
+      // underlying above 3 usd pushes price below lower limit
+      const frozen = await setUnderlyingPrice(new anchor.BN(3.5 * 1e4))
+      assert.ok(frozen.price.eq(new anchor.BN(1 * 1e4)))
+      assert.ok(frozen.frozen === true)
+      const unfrozen = await setUnderlyingPrice(entryPrice)
+      assert.ok(unfrozen.price.eq(new anchor.BN(1 * 1e4)))
 
       const { userSystemAccount, userWallet } = await createAccountWithCollateral({
         collateralAccount,
@@ -492,53 +2112,220 @@ describe('system', () => {
         systemProgram,
         amount: new anchor.BN(100 * 1e8)
       })
-      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const userInverseTokenAccount = await inverseToken.createAccount(userWallet.publicKey)
+      const mintedSyntheticUsd = new anchor.BN(1e8)
       await mintUsd({
         userWallet,
         systemProgram,
         userSystemAccount,
-        userTokenAccount,
+        userTokenAccount: userSyntheticUsdAccount,
         mintAuthority,
-        mintAmount: mintAmount
+        mintAmount: mintedSyntheticUsd
       })
-      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], tou64(burnAmount))
-      await systemProgram.state.rpc.burn(burnAmount, {
+      const state = await systemProgram.state()
+      try {
+        await systemProgram.state.rpc.swap(mintedSyntheticUsd, new anchor.BN(0), {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            tokenIn: syntheticUsd.publicKey,
+            tokenFor: inverseToken.publicKey,
+            userTokenAccountIn: userSyntheticUsdAccount,
+            userTokenAccountFor: userInverseTokenAccount,
+            usdToken: syntheticUsd.publicKey,
+            treasury: treasury,
+            insuranceFund: insuranceFund,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Asset is frozen')
+      }
+    })
+  })
+  describe('#addIndexAsset()', () => {
+    it('prices index from constituent feeds', async () => {
+      const indexToken = await createToken({ connection, mintAuthority, wallet })
+      const firstFeed = await createPriceFeed({
+        admin,
+        oracleProgram,
+        initPrice: new anchor.BN(10 * 1e4)
+      })
+      const secondFeed = await createPriceFeed({
+        admin,
+        oracleProgram,
+        initPrice: new anchor.BN(40 * 1e4)
+      })
+      // 2 units of first and half of second
+      await systemProgram.state.rpc.addIndexAsset(
+        Buffer.from('xIDX'),
+        [firstFeed.publicKey, secondFeed.publicKey],
+        [new anchor.BN(2 * 1e4), new anchor.BN(0.5 * 1e4)],
+        {
+          accounts: {
+            assetAddress: indexToken.publicKey,
+            admin: wallet.publicKey
+          },
+          signers: [wallet]
+        }
+      )
+      const updateIndexPrice = async (feeds) => {
+        await systemProgram.state.rpc.updateIndexPrice({
+          accounts: {
+            assetAddress: indexToken.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          },
+          remainingAccounts: feeds.map((feed) => ({
+            pubkey: feed.publicKey,
+            isWritable: false,
+            isSigner: false
+          }))
+        })
+      }
+      try {
+        await updateIndexPrice([secondFeed, firstFeed])
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), "Price feeds don't match index constituents")
+      }
+      await sleep(2000)
+      await oracleProgram.rpc.setPrice(new anchor.BN(12 * 1e4), {
         accounts: {
-          authority: mintAuthority,
-          mint: syntheticUsd.publicKey,
-          userAccount: userSystemAccount.publicKey,
-          userTokenAccount: userTokenAccount,
-          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
-          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
-          owner: userWallet.publicKey
+          priceFeed: firstFeed.publicKey,
+          admin: admin.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
         },
-        signers: [userWallet]
+        signers: [admin]
       })
-      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
-      const stateAfter = await systemProgram.state()
-      const systemAccountAfter = await systemProgram.account.userAccount(
-        userSystemAccount.publicKey
-      )
-      assert.ok(
-        stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply.add(mintAmount.sub(burnAmount)))
-      )
-      assert.ok(accountAfter.amount.eq(mintAmount.sub(burnAmount)))
-
-      // we burn 1/5 of shares
-      assert.ok(
-        systemAccountAfter.shares.eq(initialShares.sub(initialShares.div(new anchor.BN(5))))
-      )
-      assert.ok(
-        stateAfter.shares.eq(
-          stateBefore.shares.add(initialShares.sub(initialShares.div(new anchor.BN(5))))
-        )
-      )
+      await updateIndexPrice([firstFeed, secondFeed])
+      const state = await systemProgram.state()
+      const index = state.assets.find((asset) => asset.assetAddress.equals(indexToken.publicKey))
+      assert.ok(index.price.eq(new anchor.BN(44 * 1e4)))
+      // staleness comes from older second feed
+      const secondFeedAccount = await oracleProgram.account.priceFeed(secondFeed.publicKey)
+      assert.ok(index.lastUpdate.eq(secondFeedAccount.lastUpdate))
     })
-    it('burn over limit', async () => {
-      const stateBefore = await systemProgram.state()
-
-      const mintAmount = new u64(1e8)
-      const burnAmount = mintAmount.mul(new anchor.BN(2))
+  })
+  describe('#rebalance()', () => {
+    it('applies leverage since last rebalance', async () => {
+      const leveragedToken = await createToken({ connection, mintAuthority, wallet })
+      const underlyingFeed = await createPriceFeed({
+        admin,
+        oracleProgram,
+        initPrice: new anchor.BN(10 * 1e4)
+      })
+      const initialPrice = new anchor.BN(1e4)
+      await systemProgram.state.rpc.addLeveragedAsset(Buffer.from('x2L'), 2, initialPrice, {
+        accounts: {
+          assetAddress: leveragedToken.publicKey,
+          feedAddress: underlyingFeed.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const rebalance = async () => {
+        await systemProgram.state.rpc.rebalance({
+          accounts: {
+            assetAddress: leveragedToken.publicKey,
+            priceFeedAccount: underlyingFeed.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          }
+        })
+      }
+      const getAsset = async () => {
+        const state = await systemProgram.state()
+        return state.assets.find((asset) => asset.assetAddress.equals(leveragedToken.publicKey))
+      }
+      // first rebalance only sets checkpoint
+      await rebalance()
+      const rebalanced = await getAsset()
+      assert.ok(rebalanced.price.eq(initialPrice))
+      assert.ok(rebalanced.checkpointFeedPrice.eq(new anchor.BN(10 * 1e4)))
+      try {
+        await rebalance()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Rebalance period has not passed')
+      }
+      // +10% of underlying => +20%
+      await oracleProgram.rpc.setPrice(new anchor.BN(11 * 1e4), {
+        accounts: {
+          priceFeed: underlyingFeed.publicKey,
+          admin: admin.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        },
+        signers: [admin]
+      })
+      await systemProgram.state.rpc.updatePrice({
+        accounts: {
+          priceFeedAccount: underlyingFeed.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
+      })
+      const asset = await getAsset()
+      assert.ok(asset.price.eq(new anchor.BN(1.2 * 1e4)))
+      // -50% of underlying => -100%, frozen at floor price
+      await oracleProgram.rpc.setPrice(new anchor.BN(5 * 1e4), {
+        accounts: {
+          priceFeed: underlyingFeed.publicKey,
+          admin: admin.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        },
+        signers: [admin]
+      })
+      await systemProgram.state.rpc.updatePrice({
+        accounts: {
+          priceFeedAccount: underlyingFeed.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
+      })
+      const frozenAsset = await getAsset()
+      assert.ok(frozenAsset.frozen)
+      assert.ok(frozenAsset.price.eq(new anchor.BN(1)))
+      try {
+        await rebalance()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Asset is frozen')
+      }
+    })
+  })
+  describe('#settle()', () => {
+    it('settles expired asset and redeems it for synthetic usd', async () => {
+      const expiringToken = await createToken({ connection, mintAuthority, wallet })
+      const tokenFeed = await createPriceFeed({ admin, oracleProgram })
+      await systemProgram.state.rpc.addAsset(Buffer.from('xEXP'), {
+        accounts: {
+          assetAddress: expiringToken.publicKey,
+          feedAddress: tokenFeed.publicKey,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
+      const setAssetExpiry = async (expiry) => {
+        await systemProgram.state.rpc.setAssetExpiry(new anchor.BN(expiry), {
+          accounts: {
+            assetAddress: expiringToken.publicKey,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            admin: wallet.publicKey
+          },
+          signers: [wallet]
+        })
+      }
+      const expiry = (await getClockTimestamp(connection)) + 4
+      try {
+        await setAssetExpiry(expiry - 10)
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Parameter out of range')
+      }
+      await setAssetExpiry(expiry)
       const { userSystemAccount, userWallet } = await createAccountWithCollateral({
         collateralAccount,
         collateralToken,
@@ -546,72 +2333,148 @@ describe('system', () => {
         systemProgram,
         amount: new anchor.BN(100 * 1e8)
       })
-      const userTokenAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
+      const userExpiringTokenAccount = await expiringToken.createAccount(userWallet.publicKey)
+      const mintedSyntheticUsd = new anchor.BN(10 * 1e8)
       await mintUsd({
         userWallet,
         systemProgram,
         userSystemAccount,
-        userTokenAccount,
+        userTokenAccount: userSyntheticUsdAccount,
         mintAuthority,
-        mintAmount: mintAmount
+        mintAmount: mintedSyntheticUsd
+      })
+      await syntheticUsd.approve(
+        userSyntheticUsdAccount,
+        mintAuthority,
+        userWallet,
+        [],
+        tou64(mintedSyntheticUsd)
+      )
+      const swapToExpiring = async (amount) => {
+        const state = await systemProgram.state()
+        await systemProgram.state.rpc.swap(amount, new anchor.BN(0), {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            tokenIn: syntheticUsd.publicKey,
+            tokenFor: expiringToken.publicKey,
+            userTokenAccountIn: userSyntheticUsdAccount,
+            userTokenAccountFor: userExpiringTokenAccount,
+            usdToken: syntheticUsd.publicKey,
+            treasury: treasury,
+            insuranceFund: insuranceFund,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+      }
+      await swapToExpiring(mintedSyntheticUsd.div(new anchor.BN(2)))
+      await waitForTimestamp(connection, expiry)
+      try {
+        await swapToExpiring(mintedSyntheticUsd.div(new anchor.BN(2)))
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Asset expired')
+      }
+      // Price published after expiry is ignored
+      await oracleProgram.rpc.setPrice(new anchor.BN(4 * 1e4), {
+        accounts: {
+          priceFeed: tokenFeed.publicKey,
+          admin: admin.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        },
+        signers: [admin]
+      })
+      await systemProgram.state.rpc.updatePrice({
+        accounts: {
+          priceFeedAccount: tokenFeed.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
+      })
+      await systemProgram.state.rpc.settle({
+        accounts: {
+          assetAddress: expiringToken.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+        }
       })
+      // Settled at last price recorded before expiry
+      const settlementPrice = new anchor.BN(2 * 1e4)
+      const state = await systemProgram.state()
+      const settled = state.assets.find((asset) =>
+        asset.assetAddress.equals(expiringToken.publicKey)
+      )
+      assert.ok(settled.frozen === true)
+      assert.ok(settled.price.eq(settlementPrice))
+      assert.ok(settled.lastUpdate.lt(new anchor.BN(expiry)))
 
-      await syntheticUsd.approve(userTokenAccount, mintAuthority, userWallet, [], tou64(burnAmount))
-      await systemProgram.state.rpc.burn(burnAmount, {
+      const expiringTokenInfo = await expiringToken.getAccountInfo(userExpiringTokenAccount)
+      const usdBefore = await syntheticUsd.getAccountInfo(userSyntheticUsdAccount)
+      await expiringToken.approve(
+        userExpiringTokenAccount,
+        mintAuthority,
+        userWallet,
+        [],
+        expiringTokenInfo.amount
+      )
+      await systemProgram.state.rpc.redeem(expiringTokenInfo.amount, {
         accounts: {
           authority: mintAuthority,
-          mint: syntheticUsd.publicKey,
-          userAccount: userSystemAccount.publicKey,
-          userTokenAccount: userTokenAccount,
           tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          mint: expiringToken.publicKey,
+          userTokenAccount: userExpiringTokenAccount,
+          usdToken: syntheticUsd.publicKey,
+          userUsdAccount: userSyntheticUsdAccount,
           clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
           owner: userWallet.publicKey
         },
         signers: [userWallet]
       })
-      const accountAfter = await syntheticUsd.getAccountInfo(userTokenAccount)
+      const usdAfter = await syntheticUsd.getAccountInfo(userSyntheticUsdAccount)
+      assert.ok(
+        usdAfter.amount.eq(
+          usdBefore.amount.add(expiringTokenInfo.amount.mul(settlementPrice).div(new u64(1e4)))
+        )
+      )
+      await systemProgram.state.rpc.delistAsset({
+        accounts: {
+          assetAddress: expiringToken.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          admin: wallet.publicKey
+        },
+        signers: [wallet]
+      })
       const stateAfter = await systemProgram.state()
-      const systemAccountAfter = await systemProgram.account.userAccount(
-        userSystemAccount.publicKey
+      assert.ok(
+        !stateAfter.assets.some((asset) => asset.assetAddress.equals(expiringToken.publicKey))
       )
-      assert.ok(stateAfter.assets[0].supply.eq(stateBefore.assets[0].supply))
-      assert.ok(accountAfter.amount.eq(new anchor.BN(0)))
-
-      assert.ok(systemAccountAfter.shares.eq(new anchor.BN(0)))
-      assert.ok(stateAfter.shares.eq(stateBefore.shares))
     })
   })
-  describe('#swap(', () => {
-    it('swaps synthetic usd to other token', async () => {
-      const tokenPrice = new anchor.BN(2 * 1e4)
-      const mintedSyntheticUsd = new anchor.BN(100 * 1e8)
-      const newToken = await createToken({ connection, mintAuthority, wallet })
-      const tokenFeed = await createPriceFeed({ admin, oracleProgram, tokenPrice })
-
-      // TODO: Create and Add price feed to this new token
-      await systemProgram.state.rpc.addAsset(Buffer.from('test'), {
+  describe('#setAssetTrading()', () => {
+    it('blocks swaps of halted asset', async () => {
+      const haltedToken = await createToken({ connection, mintAuthority, wallet })
+      const tokenFeed = await createPriceFeed({ admin, oracleProgram })
+      await systemProgram.state.rpc.addAsset(Buffer.from('xEQT'), {
         accounts: {
-          assetAddress: newToken.publicKey,
+          assetAddress: haltedToken.publicKey,
           feedAddress: tokenFeed.publicKey,
           admin: wallet.publicKey
         },
-        signer: [wallet]
+        signers: [wallet]
       })
-      const state = await systemProgram.state()
-      assert.ok(state.assets.length === 3)
-      assert.ok(state.assets[2].feedAddress.equals(tokenFeed.publicKey))
-      assert.ok(state.assets[2].assetAddress.equals(newToken.publicKey))
       const { userSystemAccount, userWallet } = await createAccountWithCollateral({
         collateralAccount,
         collateralToken,
         mintAuthority: wallet,
         systemProgram,
-        amount: new anchor.BN(10000 * 1e8)
+        amount: new anchor.BN(100 * 1e8)
       })
       const userSyntheticUsdAccount = await syntheticUsd.createAccount(userWallet.publicKey)
-      const userNewTokenAccount = await newToken.createAccount(userWallet.publicKey)
-      const stateUpdated = await systemProgram.state()
-
+      const userHaltedTokenAccount = await haltedToken.createAccount(userWallet.publicKey)
+      const mintedSyntheticUsd = new anchor.BN(10 * 1e8)
       await mintUsd({
         userWallet,
         systemProgram,
@@ -627,26 +2490,66 @@ describe('system', () => {
         [],
         tou64(mintedSyntheticUsd)
       )
-
-      await systemProgram.state.rpc.swap(mintedSyntheticUsd, {
-        accounts: {
-          userAccount: userSystemAccount.publicKey,
-          authority: mintAuthority,
-          tokenIn: syntheticUsd.publicKey,
-          tokenFor: newToken.publicKey,
-          userTokenAccountIn: userSyntheticUsdAccount,
-          userTokenAccountFor: userNewTokenAccount,
-          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
-          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
-          owner: userWallet.publicKey
-        },
-        signers: [userWallet],
-        instructions: await updateAllFeeds(stateUpdated, systemProgram)
-      })
-      const accountUsd = await syntheticUsd.getAccountInfo(userSyntheticUsdAccount)
-      const accountNewToken = await newToken.getAccountInfo(userNewTokenAccount)
-      assert.ok(accountUsd.amount.eq(new anchor.BN(0)))
-      assert.ok(accountNewToken.amount.eq(new anchor.BN('4985000000')))
+      const swapToHalted = async () => {
+        const state = await systemProgram.state()
+        await systemProgram.state.rpc.swap(new anchor.BN(1e8), new anchor.BN(0), {
+          accounts: {
+            userAccount: userSystemAccount.publicKey,
+            authority: mintAuthority,
+            tokenIn: syntheticUsd.publicKey,
+            tokenFor: haltedToken.publicKey,
+            userTokenAccountIn: userSyntheticUsdAccount,
+            userTokenAccountFor: userHaltedTokenAccount,
+            usdToken: syntheticUsd.publicKey,
+            treasury: treasury,
+            insuranceFund: insuranceFund,
+            tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+            owner: userWallet.publicKey
+          },
+          signers: [userWallet],
+          instructions: await updateAllFeeds(state, systemProgram)
+        })
+      }
+      const setMarketClosed = async (marketClosed) => {
+        await oracleProgram.rpc.setMarketClosed(marketClosed, {
+          accounts: {
+            priceFeed: tokenFeed.publicKey,
+            admin: admin.publicKey
+          },
+          signers: [admin]
+        })
+      }
+      const setHalted = async (halted) => {
+        await systemProgram.state.rpc.setAssetTrading(halted, 0, 0, {
+          accounts: {
+            assetAddress: haltedToken.publicKey,
+            admin: wallet.publicKey
+          },
+          signers: [wallet]
+        })
+      }
+      // market closed bit of feed is applied on price update
+      await setMarketClosed(true)
+      try {
+        await swapToHalted()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Trading of asset is halted')
+      }
+      await setMarketClosed(false)
+      await swapToHalted()
+      await setHalted(true)
+      try {
+        await swapToHalted()
+        assert.ok(false)
+      } catch (error) {
+        assert.equal(error.toString(), 'Trading of asset is halted')
+      }
+      await setHalted(false)
+      await swapToHalted()
+      const info = await haltedToken.getAccountInfo(userHaltedTokenAccount)
+      assert.ok(info.amount.gt(new u64(0)))
     })
   })
   it('#createUserAccount()', async () => {
@@ -658,8 +2561,9 @@ describe('system', () => {
         rent: anchor.web3.SYSVAR_RENT_PUBKEY
       },
       signers: [userAccount],
-      // Auto allocate memory
-      instructions: [await systemProgram.account.userAccount.createInstruction(userAccount)]
+      instructions: [
+        await systemProgram.account.userAccount.createInstruction(userAccount, USER_ACCOUNT_SIZE)
+      ]
     })
     const account = await systemProgram.account.userAccount(userAccount.publicKey)
     assert.ok(account.shares.eq(new anchor.BN(0)))
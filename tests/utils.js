@@ -1,6 +1,9 @@
 const { Token, u64 } = require('@solana/spl-token')
 const TokenInstructions = require('@project-serum/serum').TokenInstructions
 const anchor = require('@project-serum/anchor')
+// Leaves space for vesting entries
+const USER_ACCOUNT_SIZE = 400
+const INDEX_KIND = 2
 const createToken = async ({ connection, wallet, mintAuthority }) => {
   const token = await Token.createMint(
     connection,
@@ -27,8 +30,9 @@ const createAccountWithCollateral = async ({
       rent: anchor.web3.SYSVAR_RENT_PUBKEY
     },
     signers: [userAccount],
-    // Auto allocate memory
-    instructions: [await systemProgram.account.userAccount.createInstruction(userAccount)]
+    instructions: [
+      await systemProgram.account.userAccount.createInstruction(userAccount, USER_ACCOUNT_SIZE)
+    ]
   })
   const userCollateralTokenAccount = await collateralToken.createAccount(userWallet.publicKey)
   await collateralToken.mintTo(
@@ -67,7 +71,8 @@ const createPriceFeed = async ({
   await oracleProgram.rpc.create(admin.publicKey, initPrice, ticker, {
     accounts: {
       priceFeed: collateralTokenFeed.publicKey,
-      rent: anchor.web3.SYSVAR_RENT_PUBKEY
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
     },
     signers: [collateralTokenFeed],
     instructions: [
@@ -78,18 +83,36 @@ const createPriceFeed = async ({
 }
 const updateAllFeeds = async (state, systemProgram) => {
   // first token is synthetic usd
-  const transactions = []
-  for (let index = 1; index < state.assets.length; index++) {
-    transactions.push(
-      await systemProgram.state.instruction.updatePrice(state.assets[index].feedAddress, {
-        accounts: {
-          priceFeedAccount: state.assets[index].feedAddress,
-          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
-        }
-      })
-    )
-  }
-  return transactions
+  const remainingAccounts = state.assets.slice(1).map((asset) => ({
+    pubkey: asset.feedAddress,
+    isWritable: false,
+    isSigner: false
+  }))
+  const updatePrices = await systemProgram.state.instruction.updatePrices({
+    accounts: {
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+    },
+    remainingAccounts
+  })
+  // index assets are priced from their constituents
+  const updateIndexPrices = await Promise.all(
+    state.assets
+      .filter((asset) => asset.kind === INDEX_KIND)
+      .map((asset) =>
+        systemProgram.state.instruction.updateIndexPrice({
+          accounts: {
+            assetAddress: asset.assetAddress,
+            clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+          },
+          remainingAccounts: asset.constituents.map((constituent) => ({
+            pubkey: constituent.feedAddress,
+            isWritable: false,
+            isSigner: false
+          }))
+        })
+      )
+  )
+  return [updatePrices, ...updateIndexPrices]
 }
 const mintUsd = async ({
   userWallet,
@@ -115,6 +138,40 @@ const mintUsd = async ({
     instructions: updateAllFeedsTxs
   })
 }
+// Position is only logged so instruction is simulated
+const getUserPosition = async ({ systemProgram, userSystemAccount }) => {
+  const state = await systemProgram.state()
+  const transaction = new anchor.web3.Transaction()
+  transaction.add(...(await updateAllFeeds(state, systemProgram)))
+  transaction.add(
+    await systemProgram.state.instruction.getUserPosition({
+      accounts: {
+        userAccount: userSystemAccount.publicKey,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY
+      }
+    })
+  )
+  const { value } = await systemProgram.provider.connection.simulateTransaction(transaction, [
+    systemProgram.provider.wallet.payer
+  ])
+  const fields = {
+    collateral_value: 'collateralValue',
+    debt: 'debt',
+    pool_share: 'poolShare',
+    collateral_ratio: 'collateralRatio',
+    max_mintable: 'maxMintable',
+    max_withdrawable: 'maxWithdrawable',
+    liquidation_price: 'liquidationPrice'
+  }
+  const position = {}
+  value.logs.forEach((log) => {
+    const match = log.match(/^Program log: (\w+) (\d+)$/)
+    if (match && fields[match[1]]) {
+      position[fields[match[1]]] = new anchor.BN(match[2])
+    }
+  })
+  return position
+}
 const tou64 = (amount) => {
   // eslint-disable-next-line new-cap
   return new u64(amount.toString())
@@ -140,13 +197,29 @@ const newAccountWithLamports = async (connection, lamports = 1e10) => {
 const sleep = (ms) => {
   return new Promise((resolve) => setTimeout(resolve, ms))
 }
+const getClockTimestamp = async (connection) => {
+  const info = await connection.getAccountInfo(anchor.web3.SYSVAR_CLOCK_PUBKEY)
+  // unix_timestamp follows slot, epoch_start_timestamp, epoch and leader_schedule_epoch
+  return new anchor.BN(info.data.slice(32, 40), 'le').toNumber()
+}
+// Validator clock can drift from local time so wait for it directly
+const waitForTimestamp = async (connection, timestamp) => {
+  while ((await getClockTimestamp(connection)) < timestamp) {
+    await sleep(500)
+  }
+}
 
 module.exports = {
   createToken,
   createAccountWithCollateral,
   createPriceFeed,
   mintUsd,
+  getUserPosition,
   updateAllFeeds,
   tou64,
-  newAccountWithLamports
+  newAccountWithLamports,
+  sleep,
+  getClockTimestamp,
+  waitForTimestamp,
+  USER_ACCOUNT_SIZE
 }